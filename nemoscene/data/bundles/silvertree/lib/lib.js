@@ -27,4 +27,51 @@ class Silvertree {
             return false;
         }
     }
+
+    static socket = null;
+    static handlers = {};
+
+    static connect() {
+        if (this.socket) {
+            return this.socket;
+        }
+        this.socket = new WebSocket(this.serverAddress.replace(/^http/, "ws") + "/ws");
+        this.socket.onopen = () => {
+            for (const topic in this.handlers) {
+                this.socket.send(JSON.stringify({type: "subscribe", topic: topic}));
+            }
+        };
+        this.socket.onmessage = (event) => {
+            let message = JSON.parse(event.data);
+            for (const handler of this.handlers[message.topic] || []) {
+                handler(message.data);
+            }
+        };
+        this.socket.onclose = () => {
+            this.socket = null;
+            setTimeout(() => this.connect(), 1000);
+        };
+        return this.socket;
+    }
+
+    static subscribe(topic, handler) {
+        if (!this.handlers[topic]) {
+            this.handlers[topic] = [];
+            let socket = this.connect();
+            if (socket.readyState === WebSocket.OPEN) {
+                socket.send(JSON.stringify({type: "subscribe", topic: topic}));
+            }
+        }
+        this.handlers[topic].push(handler);
+    }
+
+    static publish(topic, data) {
+        let socket = this.connect();
+        let message = JSON.stringify({type: "publish", topic: topic, data: data});
+        if (socket.readyState === WebSocket.OPEN) {
+            socket.send(message);
+        } else {
+            socket.addEventListener("open", () => socket.send(message), {once: true});
+        }
+    }
 }
\ No newline at end of file
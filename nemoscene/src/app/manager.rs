@@ -1,4 +1,7 @@
 use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail};
 use gtk::Application;
 use log::{error, info};
 use walkdir::WalkDir;
@@ -6,19 +9,22 @@ use crate::app::Bundle;
 use crate::configuration::ConfigurationRegistry;
 
 pub struct AppManager {
+    /// The folder bundles are loaded from and installed into.
+    path: PathBuf,
     bundles: BTreeMap<String, Bundle>,
 }
 
 impl AppManager {
-    pub fn new() -> AppManager {
+    pub fn new(path: PathBuf) -> AppManager {
         AppManager {
+            path,
             bundles: BTreeMap::new(),
         }
     }
 
     pub fn init(&mut self, configuration: &mut ConfigurationRegistry) {
         info!("Loading bundles");
-        for folder in WalkDir::new("data/bundles").min_depth(1).max_depth(1) {
+        for folder in WalkDir::new(&self.path).min_depth(1).max_depth(1) {
             match folder {
                 Ok(folder) => if folder.file_type().is_dir() {
                     let path = folder.path().to_str().unwrap();
@@ -42,4 +48,68 @@ impl AppManager {
     pub fn get_bundle(&self, uuid: &str) -> Option<&Bundle> {
         self.bundles.get(uuid)
     }
-}
\ No newline at end of file
+
+    pub fn bundles(&self) -> impl Iterator<Item = &Bundle> {
+        self.bundles.values()
+    }
+
+    /// Copies a bundle directory into the bundles folder and loads it. Returns the bundle's uuid.
+    pub fn install_bundle(&mut self, source: &Path, configuration: &mut ConfigurationRegistry) -> anyhow::Result<String> {
+        let uuid = Bundle::load_bundle(source.to_str().ok_or(anyhow!("Invalid bundle path"))?)?.uuid;
+        if self.bundles.contains_key(&uuid) {
+            bail!("Bundle {} is already installed", uuid);
+        }
+        let target = self.path.join(&uuid);
+        if target.exists() {
+            bail!("Bundle folder {} already exists", target.display());
+        }
+        for entry in WalkDir::new(source) {
+            let entry = entry?;
+            let destination = target.join(entry.path().strip_prefix(source)?);
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&destination)?;
+            } else {
+                fs::copy(entry.path(), &destination)?;
+            }
+        }
+        let bundle = Bundle::load_bundle(target.to_str().unwrap())?;
+        bundle.load_configuration(configuration)?;
+        info!("Installed bundle {}", uuid);
+        self.bundles.insert(uuid.clone(), bundle);
+        Ok(uuid)
+    }
+
+    /// Removes a bundle from the running instance and unloads its configuration.
+    /// The bundle stays on disk and is loaded again on the next reload.
+    pub fn unload_bundle(&mut self, uuid: &str, configuration: &mut ConfigurationRegistry) -> anyhow::Result<Bundle> {
+        let bundle = self.bundles.remove(uuid).ok_or(anyhow!("Bundle not found: {}", uuid))?;
+        configuration.unload_all(PathBuf::from(&bundle.base_path).join("config").to_str().unwrap())?;
+        info!("Unloaded bundle {}", uuid);
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn bundles_are_installed_into_the_managed_folder() {
+        let path = std::env::temp_dir().join(format!("nemoscene-bundles-test-{}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/bundles/clock");
+        let mut configuration = ConfigurationRegistry::new();
+        let mut manager = AppManager::new(path.clone());
+        manager.init(&mut configuration);
+        assert_eq!(manager.bundles().count(), 0);
+        assert_eq!(manager.install_bundle(&source, &mut configuration).unwrap(), "clock");
+        assert!(path.join("clock/config/bundle").is_file());
+        assert!(manager.install_bundle(&source, &mut configuration).is_err());
+
+        let mut reloaded = AppManager::new(path.clone());
+        reloaded.init(&mut ConfigurationRegistry::new());
+        assert_eq!(reloaded.get_bundle("clock").unwrap().base_path, path.join("clock").to_str().unwrap());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use anyhow::{anyhow, bail};
+use toml::Table;
+use log::error;
 use crate::configuration::{ConfigurationBase, ConfigurationRegistry};
+use crate::scheduler::task::Task;
+use crate::server::proxy::ProxyRules;
 
 pub mod manager;
 
@@ -10,21 +14,86 @@ pub struct Bundle {
     pub base_path: String,
     pub uuid: String,
     pub folders: BTreeMap<String, String>,
+    /// `Cache-Control` values for files served from each folder.
+    pub cache_control: BTreeMap<String, String>,
+    /// Media types for file extensions, overriding the server's MIME database.
+    pub content_types: BTreeMap<String, String>,
+    /// Background tasks declared in the `[tasks.<name>]` tables. Invalid tasks are logged and left out.
+    pub tasks: BTreeMap<String, Task>,
+    /// Hosts the bundle may reach through `/proxy`. Nothing is allowed if the rules are invalid.
+    pub proxy: ProxyRules,
 }
 
 impl Bundle {
     pub fn load_bundle(path: &str) -> anyhow::Result<Bundle> {
         let mut pathbuf = PathBuf::from(path);
         let bundle_info = ConfigurationBase::from_file(pathbuf.join("config").join("bundle").to_str().unwrap())?;
+        let uuid = bundle_info.get_str("uuid").ok_or(anyhow!("Invalid bundle configuration"))?;
+        if !Self::is_valid_uuid(&uuid) {
+            bail!("Invalid bundle uuid '{}' in {}", uuid, path);
+        }
         Ok(Bundle {
             base_path: path.to_string(),
-            uuid: bundle_info.get_str("uuid").ok_or(anyhow!("Invalid bundle configuration"))?,
+            uuid,
             folders: bundle_info.get_string_array("folders").ok_or(anyhow!("Invalid bundle configuration"))?.iter().map(|f| (f.clone(), pathbuf.join(f).to_str().unwrap().to_string())).collect(),
+            cache_control: bundle_info.get_table("cache_control").map_or(BTreeMap::new(), |t| {
+                t.iter().filter_map(|(folder, value)| value.as_str().map(|v| (folder.clone(), v.to_string()))).collect()
+            }),
+            content_types: bundle_info.get_table("content_types").map_or(BTreeMap::new(), |t| {
+                t.iter().filter_map(|(extension, value)| value.as_str().map(|v| (extension.to_ascii_lowercase(), v.to_string()))).collect()
+            }),
+            tasks: bundle_info.get_table("tasks").map_or(BTreeMap::new(), |t| Self::load_tasks(path, t)),
+            proxy: bundle_info.get_table("proxy").map_or(Ok(ProxyRules::default()), ProxyRules::from_table).unwrap_or_else(|error| {
+                error!("Invalid proxy configuration in bundle {}: {}", path, error);
+                ProxyRules::default()
+            }),
         })
     }
 
+    /// The uuid names the bundle's folder and its data files, so it must be a single safe path component.
+    fn is_valid_uuid(uuid: &str) -> bool {
+        !uuid.is_empty() && uuid.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    fn load_tasks(path: &str, tasks: &Table) -> BTreeMap<String, Task> {
+        tasks.iter().filter_map(|(name, task)| {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                error!("Invalid task name '{}' in bundle {}", name, path);
+                return None;
+            }
+            let task = task.as_table().ok_or(anyhow!("Task must be a table")).and_then(|t| Task::from_table(Path::new(path), t));
+            match task {
+                Ok(task) => Some((name.clone(), task)),
+                Err(error) => {
+                    error!("Invalid task '{}' in bundle {}: {}", name, path, error);
+                    None
+                }
+            }
+        }).collect()
+    }
+
+    /// Files are revalidated on every use unless the bundle declares otherwise for the folder.
+    pub fn cache_control(&self, folder: &str) -> &str {
+        self.cache_control.get(folder).map_or("no-cache", |c| c.as_str())
+    }
+
     pub fn load_configuration(&self, configuration: &mut ConfigurationRegistry) -> anyhow::Result<()> {
         let config_path = PathBuf::from(self.base_path.clone()).join("config");
         configuration.load_all(config_path.to_str().unwrap())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuids_are_single_path_components() {
+        for uuid in ["clock", "4f9c2d0e-8b1a-4c3e-9f7d-2a6b5c8e1d03", "weather_v2"] {
+            assert!(Bundle::is_valid_uuid(uuid), "{}", uuid);
+        }
+        for uuid in ["", ".", "..", "../clock", "clock/..", "/etc", "a b", "clock\\x"] {
+            assert!(!Bundle::is_valid_uuid(uuid), "{}", uuid);
+        }
+    }
+}
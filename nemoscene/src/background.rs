@@ -0,0 +1,41 @@
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Upper bound for sleeping between checks, so clock adjustments are picked up.
+pub const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Runs `tick` with `state` locked until it returns `None`. Between calls the lock is released
+/// and the loop sleeps for the returned time, at most `MAX_SLEEP`, or until `wakeup` is notified.
+/// Used by the services that do work at scheduled times, such as tasks and data providers.
+pub fn run_loop<S>(state: &Mutex<S>, wakeup: &Condvar, mut tick: impl FnMut(&mut S) -> Option<Duration>) {
+    let mut guard = state.lock().unwrap_or_else(PoisonError::into_inner);
+    while let Some(sleep) = tick(&mut guard) {
+        guard = wakeup.wait_timeout(guard, sleep.min(MAX_SLEEP)).unwrap_or_else(PoisonError::into_inner).0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn notifications_end_the_sleep() {
+        let shared = Arc::new((Mutex::new(0), Condvar::new()));
+        let looping = shared.clone();
+        let started = Instant::now();
+        let handle = thread::spawn(move || run_loop(&looping.0, &looping.1, |ticks| {
+            *ticks += 1;
+            if *ticks < 3 { Some(Duration::from_secs(3600)) } else { None }
+        }));
+        while !handle.is_finished() {
+            shared.1.notify_all();
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.join().unwrap();
+        assert_eq!(*shared.0.lock().unwrap(), 3);
+        assert!(started.elapsed() < MAX_SLEEP);
+    }
+}
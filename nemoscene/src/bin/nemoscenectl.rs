@@ -0,0 +1,98 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+/// Controls a running Nemoscene instance through its control socket.
+#[derive(Parser, Debug)]
+#[command(name = "nemoscenectl", version)]
+struct Arguments {
+    /// Path of the control socket
+    #[arg(short, long, default_value = "data/nemoscene.sock")]
+    socket: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the views attached to the dashboard
+    Views,
+    /// Reload the web page of a view
+    ReloadView { uuid: String },
+    /// Show the widgets of another dashboard page
+    SwitchPage { page: String },
+    /// Set a configuration value; VALUE is parsed as JSON and falls back to a string
+    Set { base: String, key: String, value: String },
+    /// Install a bundle from a folder
+    Install { path: PathBuf },
+    /// Save a PNG of the dashboard, or of a single view
+    Screenshot {
+        output: PathBuf,
+        #[arg(long)]
+        view: Option<String>,
+    },
+    /// Reload configuration and bundles from disk
+    Reload,
+    /// Shut the instance down
+    Shutdown,
+    /// Call any control method with JSON parameters
+    Call { method: String, params: Option<String> },
+}
+
+fn main() {
+    let arguments = Arguments::parse();
+    if let Err(error) = run(arguments) {
+        eprintln!("nemoscenectl: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run(arguments: Arguments) -> anyhow::Result<()> {
+    let (method, params) = match &arguments.command {
+        Command::Views => ("list_views", Value::Null),
+        Command::ReloadView { uuid } => ("reload_view", json!({ "uuid": uuid })),
+        Command::SwitchPage { page } => ("switch_page", json!({ "page": page })),
+        Command::Set { base, key, value } => {
+            let value = serde_json::from_str::<Value>(value).unwrap_or(Value::String(value.clone()));
+            ("set_config", json!({ "base": base, "key": key, "value": value }))
+        }
+        Command::Install { path } => ("install_bundle", json!({ "path": fs::canonicalize(path)? })),
+        Command::Screenshot { view, .. } => ("screenshot", json!({ "view": view })),
+        Command::Reload => ("reload", Value::Null),
+        Command::Shutdown => ("shutdown", Value::Null),
+        Command::Call { method, params } => {
+            let params = params.as_deref().map(serde_json::from_str).transpose()?.unwrap_or(Value::Null);
+            (method.as_str(), params)
+        }
+    };
+    let result = call(&arguments.socket, method, params)?;
+    match arguments.command {
+        Command::Screenshot { output, .. } => {
+            let png = result.get("png").and_then(|p| p.as_str()).ok_or(anyhow!("Invalid screenshot response"))?;
+            fs::write(output, BASE64.decode(png)?)?;
+        }
+        _ if result.is_null() => {}
+        _ => println!("{}", serde_json::to_string_pretty(&result)?),
+    }
+    Ok(())
+}
+
+fn call(socket: &PathBuf, method: &str, params: Value) -> anyhow::Result<Value> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| anyhow!("Cannot connect to {}: {}", socket.display(), e))?;
+    writeln!(stream, "{}", json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!("{}", error.get("message").and_then(|m| m.as_str()).unwrap_or("Unknown error")));
+    }
+    Ok(response.get_mut("result").map(Value::take).unwrap_or(Value::Null))
+}
@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::path::PathBuf;
+use anyhow::anyhow;
+use clap::{Parser, ValueEnum};
+use env_logger::Env;
+use toml::{Table, Value};
+use crate::configuration::ConfigurationBase;
+use crate::logging::{LogWriter, RotatingFile};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "nemoscene", version, about = "Hypefuse dashboard runtime")]
+pub struct Arguments {
+    /// Root folder containing the configuration and bundles
+    #[arg(short, long, default_value = "data")]
+    pub data: String,
+
+    /// Address the bundle server listens on
+    #[arg(short, long, default_value = "0.0.0.0")]
+    pub bind: String,
+
+    /// Port the bundle server listens on
+    #[arg(short, long, default_value_t = 1337)]
+    pub port: u16,
+
+    /// Log filter, e.g. "debug" or "info,nemoscene::server=trace,widget::clock=warn".
+    /// Defaults to RUST_LOG, then to the configured log_level
+    #[arg(short, long)]
+    pub log_level: Option<String>,
+
+    /// Log output format. Defaults to the configured log_format
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Run only the configuration registry, bundle manager and server, without the GTK dashboard
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Override a configuration value for this run, e.g. -c dashboard.screen_width=800
+    #[arg(short = 'c', long = "config", value_name = "BASE.KEY=VALUE")]
+    pub config_overrides: Vec<String>,
+
+    /// Capture a PNG of the dashboard (or of --view) into this file and exit
+    #[arg(long, value_name = "FILE")]
+    pub screenshot: Option<String>,
+
+    /// Widget to capture with --screenshot instead of the whole window
+    #[arg(long, requires = "screenshot")]
+    pub view: Option<String>,
+
+    /// Seconds to wait for the views to load before taking the screenshot
+    #[arg(long, default_value_t = 5, requires = "screenshot")]
+    pub delay: u64,
+}
+
+impl Arguments {
+    pub fn data_path(&self, path: &str) -> String {
+        PathBuf::from(&self.data).join(path).to_str().unwrap().to_string()
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    /// Parses the `-c` overrides into (configuration base path, key, value) triples.
+    /// Values are parsed as TOML and fall back to plain strings.
+    pub fn parse_config_overrides(&self) -> anyhow::Result<Vec<(String, String, Value)>> {
+        self.config_overrides.iter().map(|o| {
+            let (path, value) = o.split_once('=').ok_or(anyhow!("Invalid configuration override: {}", o))?;
+            let (base, key) = path.rsplit_once('.').ok_or(anyhow!("Invalid configuration override: {}", o))?;
+            let value = format!("value = {}", value).parse::<Table>().ok()
+                .and_then(|mut t| t.remove("value"))
+                .unwrap_or(Value::String(value.to_string()));
+            Ok((self.data_path(&format!("configuration/{}", base)), key.to_string(), value))
+        }).collect()
+    }
+
+    pub fn init_logger(&self) {
+        let configuration = ConfigurationBase::from_file(&self.data_path("configuration/nemoscene")).ok();
+        let detailed = configuration.as_ref().and_then(|base| base.get_bool("log_detailed")).unwrap_or(true);
+        let mut builder = match &self.log_level {
+            Some(level) => {
+                let mut builder = env_logger::Builder::new();
+                builder.parse_filters(level);
+                builder
+            }
+            None => {
+                let default_level = configuration.as_ref().and_then(|base| base.get_str("log_level")).unwrap_or("info".to_string());
+                env_logger::Builder::from_env(Env::default().default_filter_or(default_level))
+            }
+        };
+        let log_format = self.log_format.unwrap_or_else(|| {
+            match configuration.as_ref().and_then(|base| base.get_str("log_format")).as_deref() {
+                Some("json") => LogFormat::Json,
+                _ => LogFormat::Text,
+            }
+        });
+        match log_format {
+            LogFormat::Json => {
+                builder.format(|buf, record| {
+                    writeln!(buf, "{}", serde_json::json!({
+                        "timestamp": buf.timestamp().to_string(),
+                        "level": record.level().to_string(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    }))
+                });
+            }
+            LogFormat::Text => if !detailed {
+                builder.format_timestamp(None).format_target(false);
+            },
+        }
+        let log_file = configuration.as_ref()
+            .map(|base| base.get_str("log_file").unwrap_or_default())
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                let max_size = configuration.as_ref().and_then(|base| base.get_i64("log_file_size")).unwrap_or(10 * 1024 * 1024);
+                let count = configuration.as_ref().and_then(|base| base.get_i64("log_file_count")).unwrap_or(5);
+                RotatingFile::new(PathBuf::from(self.data_path(&path)), max_size.max(0) as u64, count.max(0) as u32)
+            });
+        builder.target(env_logger::Target::Pipe(Box::new(LogWriter::new(log_file))));
+        builder.init();
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use anyhow::{anyhow, bail};
 use log::{error, info};
 use toml::{Table, Value};
@@ -9,6 +10,13 @@ use serde::de::StdError;
 use toml::value::{Array, Datetime};
 use walkdir::WalkDir;
 
+static COMMIT_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times a configuration base could not be written to disk.
+pub fn commit_failures() -> u64 {
+    COMMIT_FAILURES.load(Ordering::Relaxed)
+}
+
 pub struct ConfigurationRegistry {
     configuration_bases: BTreeMap<String, ConfigurationBase>,
 }
@@ -24,6 +32,10 @@ impl ConfigurationRegistry {
         self.configuration_bases.get(&path.to_string())
     }
 
+    pub fn get_base_mut(&mut self, path: &str) -> Option<&mut ConfigurationBase> {
+        self.configuration_bases.get_mut(&path.to_string())
+    }
+
     pub fn load_all(&mut self, path: &str) -> anyhow::Result<()> {
         for base in WalkDir::new(path) {
             match base {
@@ -43,18 +55,34 @@ impl ConfigurationRegistry {
     }
 
     pub fn unload_base(&mut self, path: &str) -> anyhow::Result<()> {
-        self.get_base(&path).ok_or(anyhow!("Configuration base not found"))?.commit()?;
+        self.get_base_mut(&path).ok_or(anyhow!("Configuration base not found"))?.commit()?;
         self.configuration_bases.remove(&path.to_string()).unwrap();
         Ok(())
     }
 
+    /// Commits and unloads every base whose path starts with `path`.
+    pub fn unload_all(&mut self, path: &str) -> anyhow::Result<()> {
+        let paths: Vec<String> = self.configuration_bases.keys().filter(|p| p.starts_with(path)).cloned().collect();
+        for path in paths {
+            self.unload_base(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.configuration_bases.keys()
+    }
+
     pub fn get_bases_of(&self, path: &str) -> Vec<&ConfigurationBase> {
         self.configuration_bases.iter().filter(|&(p, b)| p.starts_with(path)).map(|(_, b)| b).collect()
     }
 
-    pub fn commit(&self) -> anyhow::Result<()> {
-        for (path, base) in self.configuration_bases.iter() {
-            base.commit()?;
+    /// Writes every base with uncommitted changes back to disk.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        for (path, base) in self.configuration_bases.iter_mut() {
+            if base.is_dirty() {
+                base.commit()?;
+            }
         }
         Ok(())
     }
@@ -64,6 +92,11 @@ impl ConfigurationRegistry {
 pub struct ConfigurationBase {
     path: String,
     properties: BTreeMap<String, Value>,
+    /// Values set with `override_value`. They shadow `properties` but are never written to disk.
+    #[serde(skip)]
+    overrides: BTreeMap<String, Value>,
+    #[serde(skip)]
+    dirty: bool,
 }
 
 impl ConfigurationBase {
@@ -74,11 +107,13 @@ impl ConfigurationBase {
         Ok(ConfigurationBase {
             path: path.to_string(),
             properties,
+            overrides: BTreeMap::new(),
+            dirty: false,
         })
     }
 
     pub fn get(&self, key: &str) -> Option<&Value> {
-        self.properties.get(key)
+        self.overrides.get(key).or_else(|| self.properties.get(key))
     }
 
     pub fn get_json(&self, key: &str) -> Option<Vec<u8>> {
@@ -88,6 +123,17 @@ impl ConfigurationBase {
         None
     }
 
+    /// All values of the base as seen by the running instance, including overrides.
+    pub fn properties(&self) -> BTreeMap<String, Value> {
+        let mut properties = self.properties.clone();
+        properties.extend(self.overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+        properties
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&self.properties())?)
+    }
+
     pub fn get_i64(&self, key: &str) -> Option<i64> {
         if let Some(val) = self.get(key) {
             if let Value::Integer(val) = val {
@@ -97,6 +143,15 @@ impl ConfigurationBase {
         None
     }
 
+    /// Returns a float value. Integers are accepted as well.
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        match self.get(key)? {
+            Value::Float(val) => Some(*val),
+            Value::Integer(val) => Some(*val as f64),
+            _ => None,
+        }
+    }
+
     pub fn get_str(&self, key: &str) -> Option<String> {
         if let Some(val) = self.get(key) {
             if let Value::String(val) = val {
@@ -134,17 +189,55 @@ impl ConfigurationBase {
         None
     }
 
-    pub fn commit(&self) -> anyhow::Result<()> {
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        let result = self.write();
+        if result.is_err() {
+            COMMIT_FAILURES.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn write(&mut self) -> anyhow::Result<()> {
         let toml = toml::ser::to_string(&self.properties)?;
         fs::write(&self.path, toml.as_str())?;
+        self.dirty = false;
         Ok(())
     }
 
+    /// Sets and commits a value. An explicit change replaces an override of the same key.
     pub fn set(&mut self, key: &str, value: Value) -> anyhow::Result<()> {
+        self.overrides.remove(key);
         self.properties.insert(key.to_string(), value);
+        self.dirty = true;
         self.commit()
     }
 
+    /// Removes and commits a stored value, together with any override of it.
+    /// Overrides are not stored, so a key that is only overridden is left alone.
+    pub fn remove(&mut self, key: &str) -> anyhow::Result<Option<Value>> {
+        let value = self.properties.remove(key);
+        if value.is_some() {
+            self.overrides.remove(key);
+            self.dirty = true;
+            self.commit()?;
+        }
+        Ok(value)
+    }
+
+    /// Whether a key has a value for the running instance only.
+    pub fn is_override_only(&self, key: &str) -> bool {
+        self.overrides.contains_key(key) && !self.properties.contains_key(key)
+    }
+
+    /// Sets a value for the running instance only, without committing it to disk.
+    pub fn override_value(&mut self, key: &str, value: Value) {
+        self.overrides.insert(key.to_string(), value);
+    }
+
     pub fn set_i64(&mut self, key: &str, value: i64) -> anyhow::Result<()> {
         self.set(key, Value::Integer(value))
     }
@@ -157,3 +250,55 @@ impl ConfigurationBase {
         self.set(key, Value::Array(value.into_iter().map(|val| val.try_into()).collect::<Result<Vec<Value>, _>>().map_err(|e| anyhow!("Value type not supported"))?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_base(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("nemoscene-configuration-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn overrides_shadow_values_but_are_not_committed() {
+        let path = temporary_base("overrides", "headless = false\nport = 1\n");
+        let mut base = ConfigurationBase::from_file(&path).unwrap();
+        base.override_value("headless", Value::Boolean(true));
+        assert_eq!(base.get_bool("headless"), Some(true));
+        assert_eq!(base.properties().get("headless"), Some(&Value::Boolean(true)));
+        base.set_i64("port", 2).unwrap();
+        let written = ConfigurationBase::from_file(&path).unwrap();
+        assert_eq!(written.get_bool("headless"), Some(false));
+        assert_eq!(written.get_i64("port"), Some(2));
+        assert_eq!(base.get_bool("headless"), Some(true));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn setting_an_overridden_key_replaces_the_override() {
+        let path = temporary_base("replace", "headless = false\n");
+        let mut base = ConfigurationBase::from_file(&path).unwrap();
+        base.override_value("headless", Value::Boolean(true));
+        base.set("headless", Value::Boolean(false)).unwrap();
+        assert_eq!(base.get_bool("headless"), Some(false));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn removing_an_override_only_key_changes_nothing() {
+        let path = temporary_base("remove", "port = 1\n");
+        let mut base = ConfigurationBase::from_file(&path).unwrap();
+        base.override_value("headless", Value::Boolean(true));
+        assert!(base.is_override_only("headless"));
+        assert_eq!(base.remove("headless").unwrap(), None);
+        assert!(!base.is_dirty());
+        assert_eq!(base.get_bool("headless"), Some(true));
+        base.override_value("port", Value::Integer(2));
+        assert!(!base.is_override_only("port"));
+        assert_eq!(base.remove("port").unwrap(), Some(Value::Integer(1)));
+        assert_eq!(base.get("port"), None);
+        fs::remove_file(path).unwrap();
+    }
+}
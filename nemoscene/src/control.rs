@@ -0,0 +1,245 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::{process, thread};
+use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::dashboard::{DashboardMessage, SCREENSHOT_TIMEOUT};
+use crate::events::Event;
+use crate::server::admin;
+use crate::server::http::HttpError;
+use crate::system_state::SystemState;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(error: anyhow::Error) -> Self {
+        let code = match error.downcast_ref::<HttpError>() {
+            Some(HttpError::BadRequest(_)) | Some(HttpError::NotFound(_)) => INVALID_PARAMS,
+            _ => SERVER_ERROR,
+        };
+        RpcError { code, message: error.to_string() }
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError { code: INVALID_PARAMS, message: e.to_string() })
+}
+
+/// Listens for JSON-RPC 2.0 requests on a Unix domain socket, one request per line.
+/// The socket is only accessible to the user running Nemoscene. Returns the path of the socket.
+pub fn start(system_state: SystemState) -> anyhow::Result<PathBuf> {
+    let path = {
+        let configuration = system_state.configuration();
+        let socket = configuration.get_base(&system_state.arguments.data_path("configuration/nemoscene"))
+            .and_then(|b| b.get_str("control_socket"))
+            .unwrap_or(String::from("nemoscene.sock"));
+        PathBuf::from(system_state.arguments.data_path(&socket))
+    };
+    // A socket file left behind by an instance that did not shut down cleanly
+    if path.exists() && UnixStream::connect(&path).is_err() {
+        fs::remove_file(&path)?;
+    }
+    let listener = bind_private(&path).map_err(|e| anyhow!("Cannot create control socket {}: {}", path.display(), e))?;
+    info!("Control socket listening on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let system_state = system_state.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle_client(&system_state, stream) {
+                            debug!("Control client error: {}", error);
+                        }
+                    });
+                }
+                Err(error) => error!("Control socket error: {}", error),
+            }
+        }
+    });
+    Ok(path)
+}
+
+/// Binds the socket inside a new directory only the current user can enter, restricts the
+/// socket itself and only then moves it to `path`, so nobody else can ever connect to it.
+fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    let directory = path.with_file_name(format!(".control-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&directory)?;
+    let private_path = directory.join("socket");
+    let result = UnixListener::bind(&private_path).map_err(anyhow::Error::from).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&directory)?;
+    result
+}
+
+/// Removes the socket file so no new clients can connect.
+pub fn stop(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+fn handle_client(system_state: &SystemState, stream: UnixStream) -> anyhow::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Err(e) => error_response(Value::Null, RpcError { code: PARSE_ERROR, message: e.to_string() }),
+            Ok(request) => match serde_json::from_value::<RpcRequest>(request) {
+                Err(e) => error_response(Value::Null, RpcError { code: INVALID_REQUEST, message: e.to_string() }),
+                Ok(request) => {
+                    debug!("Control request: {}", request.method);
+                    match call(system_state, &request.method, request.params) {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+                        Err(error) => error_response(request.id, error),
+                    }
+                }
+            },
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": error.code, "message": error.message } })
+}
+
+#[derive(Deserialize)]
+struct ViewParams {
+    uuid: String,
+}
+
+#[derive(Deserialize)]
+struct PageParams {
+    page: String,
+}
+
+#[derive(Deserialize)]
+struct SetConfigParams {
+    base: String,
+    key: String,
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct InstallParams {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct ScreenshotParams {
+    view: Option<String>,
+}
+
+fn call(system_state: &SystemState, method: &str, parameters: Value) -> Result<Value, RpcError> {
+    match method {
+        "list_views" => {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            system_state.dashboard.send_message(DashboardMessage::ListViews(sender))?;
+            let views = receiver.recv_timeout(SCREENSHOT_TIMEOUT).map_err(|e| anyhow!("Dashboard did not respond: {}", e))?;
+            Ok(Value::Array(views.into_iter().map(|view| json!({
+                "uuid": view.uuid,
+                "url": view.url,
+                "position": [view.position.x_i32(), view.position.y_i32()],
+                "size": [view.size.x_i32(), view.size.y_i32()],
+                "page": view.page,
+            })).collect()))
+        }
+        "reload_view" => {
+            let params: ViewParams = params(parameters)?;
+            system_state.dashboard.send_message(DashboardMessage::ReloadView(params.uuid))?;
+            Ok(Value::Null)
+        }
+        "switch_page" => {
+            let params: PageParams = params(parameters)?;
+            let known = params.page == system_state.dashboard.start_page() || system_state.configuration()
+                .get_bases_of(&system_state.arguments.data_path("configuration/widgets")).iter()
+                .any(|base| base.get_str("page").as_deref() == Some(params.page.as_str()));
+            if !known {
+                return Err(RpcError { code: INVALID_PARAMS, message: format!("Unknown page: {}", params.page) });
+            }
+            system_state.dashboard.send_message(DashboardMessage::SwitchPage(params.page))?;
+            Ok(Value::Null)
+        }
+        "set_config" => {
+            let params: SetConfigParams = params(parameters)?;
+            let mut values = serde_json::Map::new();
+            values.insert(params.key, params.value);
+            admin::update_configuration(system_state, &params.base, values)?;
+            Ok(Value::Null)
+        }
+        "install_bundle" => {
+            let params: InstallParams = params(parameters)?;
+            let uuid = {
+                let mut configuration = system_state.configuration_mut();
+                system_state.app_manager_mut().install_bundle(Path::new(&params.path), &mut configuration)?
+            };
+            if let Some(bundle) = system_state.app_manager().get_bundle(&uuid) {
+                system_state.scheduler.load_bundle(bundle);
+            }
+            system_state.events.publish(Event::BundleLoaded { uuid: uuid.clone() });
+            Ok(json!({ "uuid": uuid }))
+        }
+        "screenshot" => {
+            let params: ScreenshotParams = params(parameters)?;
+            let receiver = system_state.dashboard.screenshot(params.view.as_deref())?;
+            let png = receiver.recv_timeout(SCREENSHOT_TIMEOUT).map_err(|e| anyhow!("Dashboard did not respond: {}", e))??;
+            Ok(json!({ "png": BASE64.encode(png) }))
+        }
+        "reload" => {
+            system_state.reload();
+            Ok(Value::Null)
+        }
+        "shutdown" => {
+            system_state.shutdown.request(0);
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("Unknown method: {}", method) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_is_private_from_the_start() {
+        let path = std::env::temp_dir().join(format!("nemoscene-control-test-{}.sock", process::id()));
+        let listener = bind_private(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_file_name(format!(".control-{}", process::id())).exists());
+        UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+(function () {
+    const handlers = window.webkit && window.webkit.messageHandlers;
+    if (!handlers || !handlers.nemoscene_console) {
+        return;
+    }
+    const describe = (value) => {
+        if (typeof value === "string") return value;
+        if (value instanceof Error) return value.stack || String(value);
+        try {
+            return JSON.stringify(value);
+        } catch (e) {
+            return String(value);
+        }
+    };
+    const send = (level, values) => {
+        try {
+            handlers.nemoscene_console.postMessage(JSON.stringify({level: level, message: values.map(describe).join(" ")}));
+        } catch (e) {
+            // Logging must never break the widget
+        }
+    };
+    for (const level of ["debug", "log", "info", "warn", "error"]) {
+        const original = console[level];
+        console[level] = function (...values) {
+            send(level, values);
+            original.apply(console, values);
+        };
+    }
+    window.addEventListener("error", (event) => send("error", [event.message + " (" + event.filename + ":" + event.lineno + ")"]));
+    window.addEventListener("unhandledrejection", (event) => send("error", ["Unhandled promise rejection:", event.reason]));
+})();
@@ -6,19 +6,28 @@ use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
 use anyhow::anyhow;
-use gtk::{CssProvider, Fixed, glib, Window, WindowType};
+use gtk::{CssProvider, Fixed, glib, Justification, Label, Window, WindowType};
 use gtk::ffi::{gtk_css_provider_get_default, gtk_css_provider_load_from_data, gtk_style_context_add_provider_for_screen, GtkStyleProvider};
 use gtk::gdk::ffi::gdk_screen_get_default;
+use gtk::gdk::prelude::WindowExtManual;
 use gtk::glib::ffi::GError;
-use gtk::prelude::{ContainerExt, CssProviderExt, GtkWindowExt, WidgetExt};
-use log::{error, info};
+use gtk::prelude::{ContainerExt, CssProviderExt, FixedExt, GtkWindowExt, LabelExt, StyleContextExt, WidgetExt};
+use log::{error, info, warn};
 use view::View;
+use webkit2gtk::WebViewExt;
 use crate::configuration::{ConfigurationBase, ConfigurationRegistry};
-use crate::dashboard::view::ViewParameters;
-use crate::system_state::SystemState;
+use crate::dashboard::view::{ViewContext, ViewParameters};
+use crate::events::Event;
+use crate::system_state::Shutdown;
 
 pub mod view;
 
+/// The page shown when the dashboard configuration has no `start_page`.
+const DEFAULT_PAGE: &str = "main";
+
+/// How long other threads wait for the UI thread to answer a screenshot or view list request.
+pub const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Copy, Clone, Debug)]
 pub struct Point {
     x: f32,
@@ -92,6 +101,16 @@ impl Point {
 pub enum DashboardMessage {
     Quit,
     AttachView(ViewParameters),
+    Screenshot(Option<String>, mpsc::Sender<anyhow::Result<Vec<u8>>>),
+    /// Moves and resizes an attached view, or attaches it if it is not shown yet.
+    UpdateView(ViewParameters),
+    ReloadView(String),
+    /// Replies with the parameters of all attached views, in screen pixels.
+    ListViews(mpsc::Sender<Vec<ViewParameters>>),
+    /// Shows a device pairing code on top of the views for the given time.
+    ShowPairingCode(String, Duration),
+    /// Shows the views of a page and hides the views of all other pages.
+    SwitchPage(String),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -118,36 +137,62 @@ impl Viewport {
 pub struct Dashboard {
     viewport: Viewport,
     channel_sender: Option<glib::Sender<DashboardMessage>>,
+    headless: bool,
+    /// Requested when the dashboard quits.
+    shutdown: Shutdown,
+    /// The page shown after startup, `start_page` in the dashboard configuration.
+    start_page: String,
 }
 
 impl Dashboard {
-    pub fn new() -> Dashboard {
+    pub fn new(shutdown: Shutdown) -> Dashboard {
         Dashboard {
             viewport: Viewport::default(),
             channel_sender: None,
+            headless: false,
+            shutdown,
+            start_page: String::from(DEFAULT_PAGE),
         }
     }
 
-    pub fn init(&mut self, config: &ConfigurationRegistry) -> anyhow::Result<()> {
+    pub fn start_page(&self) -> &str {
+        &self.start_page
+    }
+
+    /// Initializes the dashboard. In headless mode no GTK UI thread is started and
+    /// dashboard messages are discarded, so no display is required.
+    pub fn init(&mut self, config: &ConfigurationRegistry, headless: bool, context: ViewContext) -> anyhow::Result<()> {
+        let dashboard_base = context.arguments.data_path("configuration/dashboard");
         let screen_width: i32 = {
-            config.get_base("data/configuration/dashboard").ok_or(anyhow!("Cannot load configuration base"))?.get_i64("screen_width").ok_or(anyhow!("Cannot load screen size from configuration"))? as i32
+            config.get_base(&dashboard_base).ok_or(anyhow!("Cannot load configuration base"))?.get_i64("screen_width").ok_or(anyhow!("Cannot load screen size from configuration"))? as i32
         };
         let screen_height: i32 = {
-            config.get_base("data/configuration/dashboard").ok_or(anyhow!("Cannot load configuration base"))?.get_i64("screen_height").ok_or(anyhow!("Cannot load screen size from configuration"))? as i32
+            config.get_base(&dashboard_base).ok_or(anyhow!("Cannot load configuration base"))?.get_i64("screen_height").ok_or(anyhow!("Cannot load screen size from configuration"))? as i32
         };
 
-        let (sender_sender, sender_receiver) = mpsc::channel();
-
         let viewport = Viewport {
             screen_size: Point::new_i32(screen_width, screen_height),
             pixel_ratio: Point::new_f32(screen_width as f32 / 1000.00, screen_height as f32 / 1000.00),
         };
+        self.viewport = viewport;
+        self.start_page = config.get_base(&dashboard_base).and_then(|b| b.get_str("start_page")).unwrap_or(String::from(DEFAULT_PAGE));
+
+        if headless {
+            info!("Running headless, no dashboard window will be shown");
+            self.headless = true;
+            return Ok(());
+        }
+
+        let (sender_sender, sender_receiver) = mpsc::channel();
 
-        thread::spawn(move || Self::ui_thread(viewport.clone(), sender_sender));
+        let widgets_path = context.arguments.data_path("configuration/widgets");
+        let shutdown = self.shutdown.clone();
+        let page = self.start_page.clone();
+        thread::spawn(move || Self::ui_thread(viewport.clone(), context, shutdown, page, sender_sender));
 
         self.channel_sender = Some(sender_receiver.recv().expect("Sender thread sender receiver sender channel broken"));
 
-        for widget in config.get_bases_of("data/configuration/widgets") {
+        for widget in config.get_bases_of(&widgets_path) {
             if let Ok(widget) = Self::load_widget(widget) {
                 if let Err(error) = self.send_message(DashboardMessage::AttachView(widget.clone())) {
                     error!("Failed to attach widget {:#?}: {}", widget, error);
@@ -159,7 +204,7 @@ impl Dashboard {
         Ok(())
     }
 
-    fn load_widget(base: &ConfigurationBase) -> anyhow::Result<ViewParameters> {
+    pub fn load_widget(base: &ConfigurationBase) -> anyhow::Result<ViewParameters> {
         let uuid = base.get_str("uuid").ok_or(anyhow!("Invalid widget configuration"))?;
         let position_x = base.get_i64("position_x").ok_or(anyhow!("Invalid widget configuration"))? as i32;
         let position_y = base.get_i64("position_y").ok_or(anyhow!("Invalid widget configuration"))? as i32;
@@ -170,15 +215,41 @@ impl Dashboard {
             url: None,
             position: Point::new_i32(position_x, position_y),
             size: Point::new_i32(width, height),
+            page: base.get_str("page"),
         })
     }
 
     pub fn send_message(&self, message: DashboardMessage) -> anyhow::Result<()> {
+        if self.headless {
+            return match message {
+                DashboardMessage::Quit => {
+                    self.shutdown.request(0);
+                    Ok(())
+                }
+                DashboardMessage::Screenshot(_, reply) => {
+                    let _ = reply.send(Err(anyhow!("Dashboard is running headless")));
+                    Ok(())
+                }
+                DashboardMessage::ListViews(reply) => {
+                    let _ = reply.send(Vec::new());
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+        }
         self.channel_sender.as_ref().expect("Dashboard message channel not initialized yet").send(message)?;
         Ok(())
     }
 
-    fn ui_thread(viewport: Viewport, sender_sender: mpsc::Sender<glib::Sender<DashboardMessage>>) {
+    /// Requests a PNG capture of the whole window, or of a single view if `view` is given.
+    /// The capture happens on the UI thread, so the result has to be awaited on the returned receiver.
+    pub fn screenshot(&self, view: Option<&str>) -> anyhow::Result<mpsc::Receiver<anyhow::Result<Vec<u8>>>> {
+        let (sender, receiver) = mpsc::channel();
+        self.send_message(DashboardMessage::Screenshot(view.map(|v| v.to_string()), sender))?;
+        Ok(receiver)
+    }
+
+    fn ui_thread(viewport: Viewport, context: ViewContext, shutdown: Shutdown, mut page: String, sender_sender: mpsc::Sender<glib::Sender<DashboardMessage>>) {
         gtk::init().unwrap();
         unsafe { Self::load_css() };
         let window = Window::new(WindowType::Toplevel);
@@ -198,9 +269,32 @@ impl Dashboard {
             match message {
                 DashboardMessage::Quit => {
                     window.close();
-                    SystemState::shutdown();
+                    gtk::main_quit();
+                    shutdown.request(0);
+                }
+                DashboardMessage::AttachView(view) => {
+                    Self::attach_view(&window, &container, &viewport, &context, &mut views, view);
+                    Self::show_page(&views, &page);
+                }
+                DashboardMessage::Screenshot(view, reply) => Self::screenshot_view(&window, &viewport, &views, view, reply),
+                DashboardMessage::UpdateView(view) => {
+                    Self::update_view(&window, &container, &viewport, &context, &mut views, view);
+                    Self::show_page(&views, &page);
+                }
+                DashboardMessage::ReloadView(uuid) => match views.get(&uuid) {
+                    Some(view) => view.web_view().reload(),
+                    None => warn!("Cannot reload view {}: view not found", uuid),
+                },
+                DashboardMessage::ListViews(reply) => {
+                    let _ = reply.send(views.values().map(|v| v.parameters.clone()).collect());
+                }
+                DashboardMessage::ShowPairingCode(code, duration) => Self::show_pairing_code(&container, &viewport, &code, duration),
+                DashboardMessage::SwitchPage(new_page) => {
+                    info!("Switching to page {}", new_page);
+                    page = new_page;
+                    Self::show_page(&views, &page);
+                    context.events.publish(Event::PageSwitched { page: page.clone() });
                 }
-                DashboardMessage::AttachView(view) => Self::attach_view(&window, &container, &viewport, &mut views, view),
             };
             glib::ControlFlow::Continue
         });
@@ -210,14 +304,64 @@ impl Dashboard {
         gtk::main();
     }
 
-    fn attach_view(window: &Window, container: &Fixed, viewport: &Viewport, views: &mut BTreeMap<String, View>, view: ViewParameters) {
+    fn attach_view(window: &Window, container: &Fixed, viewport: &Viewport, context: &ViewContext, views: &mut BTreeMap<String, View>, view: ViewParameters) {
         let uuid = view.uuid.clone();
-        let mut view = View::new(view);
+        let mut view = View::new(view, context);
         view.parameters.position = viewport.to_actual_pixels(view.parameters.position);
         view.parameters.size = viewport.to_actual_pixels(view.parameters.size);
         views.insert(uuid.clone(), view);
         views.get(&uuid).unwrap().attach_view(container);
         window.show_all();
+        context.events.publish(Event::ViewAttached { uuid });
+    }
+
+    /// `show_all` on attaching shows every view, so this runs after each attach as well.
+    fn show_page(views: &BTreeMap<String, View>, page: &str) {
+        for view in views.values() {
+            view.web_view().set_visible(view.parameters.is_on_page(page));
+        }
+    }
+
+    fn update_view(window: &Window, container: &Fixed, viewport: &Viewport, context: &ViewContext, views: &mut BTreeMap<String, View>, parameters: ViewParameters) {
+        match views.get_mut(&parameters.uuid) {
+            Some(view) => {
+                view.parameters.position = viewport.to_actual_pixels(parameters.position);
+                view.parameters.size = viewport.to_actual_pixels(parameters.size);
+                view.web_view().set_size_request(view.parameters.size.x_i32(), view.parameters.size.y_i32());
+                container.move_(view.web_view(), view.parameters.position.x_i32(), view.parameters.position.y_i32());
+            }
+            None => Self::attach_view(window, container, viewport, context, views, parameters),
+        }
+    }
+
+    fn show_pairing_code(container: &Fixed, viewport: &Viewport, code: &str, duration: Duration) {
+        let label = Label::new(Some(&format!("Pairing code\n{}", code)));
+        label.set_justify(Justification::Center);
+        label.style_context().add_class("pairing-code");
+        let size = viewport.to_actual_pixels(Point::new_i32(400, 200));
+        let position = viewport.to_actual_pixels(Point::new_i32(300, 400));
+        label.set_size_request(size.x_i32(), size.y_i32());
+        container.put(&label, position.x_i32(), position.y_i32());
+        label.show();
+        let container = container.clone();
+        glib::timeout_add_local_once(duration, move || container.remove(&label));
+    }
+
+    fn screenshot_view(window: &Window, viewport: &Viewport, views: &BTreeMap<String, View>, view: Option<String>, reply: mpsc::Sender<anyhow::Result<Vec<u8>>>) {
+        if let Some(view) = view {
+            match views.get(&view) {
+                Some(view) => view.snapshot(move |result| { let _ = reply.send(result); }),
+                None => { let _ = reply.send(Err(anyhow!("View not found: {}", view))); }
+            }
+        } else {
+            let _ = reply.send(Self::screenshot_window(window, viewport));
+        }
+    }
+
+    fn screenshot_window(window: &Window, viewport: &Viewport) -> anyhow::Result<Vec<u8>> {
+        let gdk_window = window.window().ok_or(anyhow!("Dashboard window is not realized"))?;
+        let pixbuf = gdk_window.pixbuf(0, 0, viewport.screen_size.x_i32(), viewport.screen_size.y_i32()).ok_or(anyhow!("Cannot capture dashboard window"))?;
+        Ok(pixbuf.save_to_bufferv("png", &[])?)
     }
 
     unsafe fn load_css() {
@@ -1,7 +1,23 @@
+use anyhow::anyhow;
 use gtk::Fixed;
-use webkit2gtk::{WebContext, WebView};
+use std::sync::Arc;
+use javascriptcore::ValueExt;
+use log::{error, log, warn, Level};
+use serde::Deserialize;
+use webkit2gtk::{SnapshotOptions, SnapshotRegion, UserContentInjectedFrames, UserContentManager, UserContentManagerExt, UserScript, UserScriptInjectionTime, WebContext, WebsiteDataManager, WebView, WebViewExt};
 use crate::*;
+use crate::cli::Arguments;
 use crate::dashboard::Point;
+use crate::events::{Event, EventBus};
+use crate::server::auth::Authenticator;
+
+/// The services views use, handed to the UI thread when the dashboard starts.
+#[derive(Clone)]
+pub struct ViewContext {
+    pub events: Arc<EventBus>,
+    pub auth: Arc<Authenticator>,
+    pub arguments: Arc<Arguments>,
+}
 
 #[derive(Debug, Clone)]
 pub struct ViewParameters {
@@ -9,6 +25,23 @@ pub struct ViewParameters {
     pub url: Option<String>,
     pub position: Point,
     pub size: Point,
+    /// The dashboard page showing the view. Views without a page are shown on every page.
+    pub page: Option<String>,
+}
+
+impl ViewParameters {
+    pub fn is_on_page(&self, page: &str) -> bool {
+        self.page.as_deref().is_none_or(|p| p == page)
+    }
+}
+
+/// Forwards `console` calls and uncaught errors of the page to the `nemoscene_console` message handler.
+const CONSOLE_SCRIPT: &str = include_str!("console.js");
+
+#[derive(Debug, Deserialize)]
+struct ConsoleMessage {
+    level: String,
+    message: String,
 }
 
 pub struct View {
@@ -18,13 +51,38 @@ pub struct View {
 }
 
 impl View {
-    pub fn new(parameters: ViewParameters) -> View {
-        let web_context = WebContext::default().unwrap();
-        let web_view = WebView::with_context(&web_context);
+    /// Bundle views authenticate with a fresh view token. Every view gets its own web
+    /// context, so no view can see another view's cookie.
+    pub fn new(parameters: ViewParameters, context: &ViewContext) -> View {
+        let arguments = &context.arguments;
+        let website_data_manager = WebsiteDataManager::builder()
+            .base_data_directory(arguments.data_path(&format!("views/{}", parameters.uuid)))
+            .base_cache_directory(arguments.data_path(&format!("cache/views/{}", parameters.uuid)))
+            .build();
+        let web_context = WebContext::with_website_data_manager(&website_data_manager);
+        let user_content_manager = Self::console_capture(&parameters.uuid);
+        let web_view = WebView::builder().web_context(&web_context).user_content_manager(&user_content_manager).build();
+        let uuid = parameters.uuid.clone();
+        let events = context.events.clone();
+        let failed_events = events.clone();
+        web_view.connect_load_failed(move |_, _, url, load_error| {
+            warn!(target: &Self::log_target(&uuid), "Cannot load {}: {}", url, load_error);
+            failed_events.publish(Event::ViewLoadFailed { uuid: uuid.clone(), url: url.to_string(), error: load_error.to_string() });
+            false
+        });
+        let uuid = parameters.uuid.clone();
+        #[allow(deprecated)]
+        web_view.connect_web_process_crashed(move |_| {
+            error!(target: &Self::log_target(&uuid), "Web process crashed");
+            events.publish(Event::ViewCrashed { uuid: uuid.clone() });
+            false
+        });
         if let Some(url) = &parameters.url {
             web_view.load_uri(url.as_str());
         } else {
-            web_view.load_uri(format!("http://localhost:1337/bundle/{}", &parameters.uuid).as_str());
+            let token = context.auth.issue_view_token(&parameters.uuid);
+            let next = format!("/bundle/{}", parameters.uuid);
+            web_view.load_uri(format!("http://localhost:{}/auth/view/{}?next={}", arguments.port, token, url_escape::encode_component(&next)).as_str());
         }
         View {
             web_context,
@@ -33,14 +91,69 @@ impl View {
         }
     }
 
+    /// Widget logs use the target `widget::<uuid>`, so their level can be set per widget.
+    fn log_target(uuid: &str) -> String {
+        format!("widget::{}", uuid)
+    }
+
+    /// Logs the page's console messages and uncaught errors, tagged with the widget id.
+    fn console_capture(uuid: &str) -> UserContentManager {
+        let user_content_manager = UserContentManager::new();
+        user_content_manager.add_script(&UserScript::new(CONSOLE_SCRIPT, UserContentInjectedFrames::AllFrames, UserScriptInjectionTime::Start, &[], &[]));
+        user_content_manager.register_script_message_handler("nemoscene_console");
+        let target = Self::log_target(uuid);
+        user_content_manager.connect_script_message_received(Some("nemoscene_console"), move |_, result| {
+            let message = result.js_value().map(|v| v.to_str().to_string()).and_then(|json| serde_json::from_str::<ConsoleMessage>(&json).ok());
+            if let Some(message) = message {
+                let level = match message.level.as_str() {
+                    "error" => Level::Error,
+                    "warn" => Level::Warn,
+                    "debug" => Level::Debug,
+                    _ => Level::Info,
+                };
+                log!(target: &target, level, "{}", message.message);
+            }
+        });
+        user_content_manager
+    }
+
     pub fn attach_view(&self, fixed: &Fixed) {
         self.web_view.set_size_request(self.parameters.size.x_i32(), self.parameters.size.y_i32());
         fixed.put(&self.web_view, self.parameters.position.x_i32(), self.parameters.position.y_i32());
     }
+
+    pub fn snapshot<F: FnOnce(anyhow::Result<Vec<u8>>) + 'static>(&self, callback: F) {
+        let size = self.parameters.size;
+        self.web_view.snapshot(SnapshotRegion::Visible, SnapshotOptions::NONE, None::<&gtk::gio::Cancellable>, move |surface| {
+            callback(surface.map_err(|e| anyhow!("Cannot capture view: {}", e)).and_then(|surface| {
+                let pixbuf = gtk::gdk::pixbuf_get_from_surface(&surface, 0, 0, size.x_i32(), size.y_i32()).ok_or(anyhow!("Cannot convert view snapshot"))?;
+                Ok(pixbuf.save_to_bufferv("png", &[])?)
+            }));
+        });
+    }
 }
 
 impl<'a> View {
     pub fn web_view(&'a self) -> &'a WebView {
         &self.web_view
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_without_a_page_are_on_every_page() {
+        let view = |page: Option<&str>| ViewParameters {
+            uuid: String::from("clock"),
+            url: None,
+            position: Point::default(),
+            size: Point::default(),
+            page: page.map(String::from),
+        };
+        assert!(view(None).is_on_page("main"));
+        assert!(view(None).is_on_page("weather"));
+        assert!(view(Some("weather")).is_on_page("weather"));
+        assert!(!view(Some("weather")).is_on_page("main"));
+    }
+}
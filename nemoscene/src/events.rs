@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use log::debug;
+use serde::Serialize;
+use crate::server::pubsub::PubSub;
+
+/// Something that happened in one of the subsystems.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A configuration value was changed through the server. `key` is `None` when several keys of the base changed.
+    ConfigurationChanged { base: String, key: Option<String> },
+    BundleLoaded { uuid: String },
+    BundleUnloaded { uuid: String },
+    /// Configuration and bundles were reloaded from disk.
+    Reloaded,
+    ViewAttached { uuid: String },
+    ViewLoadFailed { uuid: String, url: String, error: String },
+    ViewCrashed { uuid: String },
+    /// The dashboard switched to another page.
+    PageSwitched { page: String },
+    /// A scheduled bundle task finished running.
+    TaskCompleted { bundle: String, task: String, success: bool },
+    ShuttingDown,
+}
+
+impl Event {
+    /// The event type as it appears in the serialized event.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::ConfigurationChanged { .. } => "configuration_changed",
+            Event::BundleLoaded { .. } => "bundle_loaded",
+            Event::BundleUnloaded { .. } => "bundle_unloaded",
+            Event::Reloaded => "reloaded",
+            Event::ViewAttached { .. } => "view_attached",
+            Event::ViewLoadFailed { .. } => "view_load_failed",
+            Event::ViewCrashed { .. } => "view_crashed",
+            Event::PageSwitched { .. } => "page_switched",
+            Event::TaskCompleted { .. } => "task_completed",
+            Event::ShuttingDown => "shutting_down",
+        }
+    }
+}
+
+/// Typed publish/subscribe between subsystems. Every subscriber receives every event
+/// on its own channel, so a slow subscriber never blocks the publisher.
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(PoisonError::into_inner);
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Returns a receiver for all events published from now on. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(PoisonError::into_inner).push(sender);
+        receiver
+    }
+
+    /// Calls `listener` for every event on a dedicated thread.
+    pub fn listen<F: Fn(Event) + Send + 'static>(&self, listener: F) {
+        let receiver = self.subscribe();
+        thread::spawn(move || {
+            for event in receiver {
+                listener(event);
+            }
+        });
+    }
+
+    /// Logs every event at debug level.
+    pub fn bridge_to_log(&self) {
+        self.listen(|event| debug!("Event: {:?}", event));
+    }
+
+    /// Forwards every event to WebSocket subscribers, on the `events` topic and on
+    /// a topic per event type such as `events.bundle_unloaded`.
+    pub fn bridge_to_pubsub(&self, pubsub: Arc<PubSub>) {
+        self.listen(move |event| {
+            let data = match serde_json::to_value(&event) {
+                Ok(data) => data,
+                Err(_) => return,
+            };
+            pubsub.publish(&format!("events.{}", event.kind()), data.clone());
+            pubsub.publish("events", data);
+        });
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use once_cell::sync::Lazy;
+
+/// Number of recent log lines kept for the admin console.
+const LOG_BUFFER_SIZE: usize = 1000;
+
+static LOG_BUFFER: Lazy<Mutex<LogBuffer>> = Lazy::new(|| Mutex::new(LogBuffer {
+    lines: VecDeque::with_capacity(LOG_BUFFER_SIZE),
+    next_sequence: 0,
+}));
+
+struct LogBuffer {
+    lines: VecDeque<(u64, String)>,
+    next_sequence: u64,
+}
+
+/// Returns the buffered log lines with a sequence number of at least `since`, oldest first.
+pub fn recent_lines(since: u64) -> Vec<(u64, String)> {
+    let buffer = LOG_BUFFER.lock().unwrap_or_else(PoisonError::into_inner);
+    buffer.lines.iter().filter(|(sequence, _)| *sequence >= since).cloned().collect()
+}
+
+/// Log file that is renamed to `<path>.1` once it exceeds `max_size`, shifting older files up to `<path>.<count>`.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    count: u32,
+    file: Option<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new(path: PathBuf, max_size: u64, count: u32) -> RotatingFile {
+        RotatingFile { path, max_size, count, file: None, size: 0 }
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.count == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for index in (1..self.count).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.open()?.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Log target that writes to stderr and an optional log file, and keeps the most recent lines in memory.
+pub struct LogWriter {
+    pending: Vec<u8>,
+    file: Option<RotatingFile>,
+}
+
+impl LogWriter {
+    pub fn new(file: Option<RotatingFile>) -> LogWriter {
+        LogWriter { pending: Vec::new(), file }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        if let Some(file) = &mut self.file {
+            // A failing log file must not take stderr logging down with it.
+            if let Err(e) = file.write_all(buf) {
+                let _ = writeln!(io::stderr(), "Cannot write log file: {}", e);
+                self.file = None;
+            }
+        }
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).to_string();
+            let mut buffer = LOG_BUFFER.lock().unwrap_or_else(PoisonError::into_inner);
+            if buffer.lines.len() == LOG_BUFFER_SIZE {
+                buffer.lines.pop_front();
+            }
+            let sequence = buffer.next_sequence;
+            buffer.next_sequence += 1;
+            buffer.lines.push_back((sequence, line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        io::stderr().flush()
+    }
+}
@@ -1,24 +1,64 @@
 #![feature(c_size_t)]
 extern crate core;
 
-use std::env;
+use std::{fs, process, thread};
+use std::time::Duration;
+use clap::Parser;
 use gtk::{prelude::*, Window, WindowType};
 use gtk::glib;
-use log::info;
+use log::{error, info};
 use webkit2gtk::WebViewExt;
+use crate::cli::Arguments;
 use crate::system_state::SystemState;
 
 pub mod server;
+mod cli;
 mod system_state;
 mod configuration;
 mod dashboard;
 mod app;
+mod background;
+mod control;
+mod events;
+mod logging;
+mod providers;
+mod scheduler;
 
 fn main() {
-
-    env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    let arguments = Arguments::parse();
+    arguments.init_logger();
     info!("Hypefuse [Nemoscene Version 0.1]");
-    get_system_state!();
-    loop{}
+    let system_state = match SystemState::init(arguments.clone()) {
+        Ok(system_state) => system_state,
+        Err(error) => {
+            error!("Cannot initialize system: {:#}", error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = system_state.handle_signals() {
+        error!("Cannot install signal handlers: {}", error);
+    }
+    if let Some(output) = arguments.screenshot.clone() {
+        let system_state = system_state.clone();
+        thread::spawn(move || {
+            match take_screenshot(&system_state, &output, arguments.view.as_deref(), Duration::from_secs(arguments.delay)) {
+                Ok(()) => system_state.shutdown.request(0),
+                Err(error) => {
+                    error!("Cannot take screenshot: {}", error);
+                    system_state.shutdown.request(1);
+                }
+            }
+        });
+    }
+    let code = system_state.shutdown.wait();
+    system_state.stop();
+    process::exit(code);
+}
+
+fn take_screenshot(system_state: &SystemState, output: &str, view: Option<&str>, delay: Duration) -> anyhow::Result<()> {
+    thread::sleep(delay);
+    let receiver = system_state.dashboard.screenshot(view)?;
+    fs::write(output, receiver.recv_timeout(dashboard::SCREENSHOT_TIMEOUT)??)?;
+    info!("Screenshot saved to {}", output);
+    Ok(())
 }
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use toml::Table;
+use crate::providers::{DataProvider, ProviderData, DEFAULT_TTL};
+use crate::server::http::UPSTREAM_TIMEOUT;
+use crate::server::secrets::{self, Secrets};
+
+const DEFAULT_BASE_URL: &str = "https://www.googleapis.com";
+
+/// Upcoming events of a public Google calendar, read with an API key. The key is kept in the
+/// secrets store under `@providers`, `api_key_secret` names it, so it never shows up in the
+/// configuration. `base_url` can point to another server implementing the Calendar API, such as a local mock.
+pub struct GoogleCalendarProvider {
+    base_url: String,
+    api_key_secret: String,
+    secrets: Arc<Secrets>,
+    calendar_id: String,
+    max_results: i64,
+    ttl: Duration,
+}
+
+impl GoogleCalendarProvider {
+    pub fn from_table(table: &Table, secrets: Arc<Secrets>) -> anyhow::Result<GoogleCalendarProvider> {
+        if table.contains_key("api_key") {
+            bail!("api_key must be stored as a secret of {} and named by api_key_secret", secrets::PROVIDER_SECRETS);
+        }
+        Ok(GoogleCalendarProvider {
+            base_url: table.get("base_url").and_then(|u| u.as_str()).unwrap_or(DEFAULT_BASE_URL).trim_end_matches('/').to_string(),
+            api_key_secret: table.get("api_key_secret").and_then(|k| k.as_str()).ok_or(anyhow!("Missing api_key_secret"))?.to_string(),
+            secrets,
+            calendar_id: table.get("calendar_id").and_then(|c| c.as_str()).ok_or(anyhow!("Missing calendar_id"))?.to_string(),
+            max_results: table.get("max_results").and_then(|m| m.as_integer()).unwrap_or(10),
+            ttl: table.get("ttl").and_then(|t| t.as_integer()).map_or(DEFAULT_TTL, |t| Duration::from_secs(t.max(1) as u64)),
+        })
+    }
+
+    fn fetch_events(&self, api_key: &str) -> anyhow::Result<ProviderData> {
+        let url = format!("{}/calendar/v3/calendars/{}/events", self.base_url, url_escape::encode_component(&self.calendar_id));
+        let response = ureq::get(&url)
+            .timeout(UPSTREAM_TIMEOUT)
+            .query("key", api_key)
+            .query("timeMin", &Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true))
+            .query("singleEvents", "true")
+            .query("orderBy", "startTime")
+            .query("maxResults", &self.max_results.to_string())
+            .call()
+            .map_err(|e| anyhow!("Calendar request failed: {}", e))?;
+        let response: Value = serde_json::from_reader(response.into_reader())?;
+        let events: Vec<Value> = response["items"].as_array().ok_or(anyhow!("Invalid calendar response"))?.iter()
+            .map(|item| json!({
+                "id": item["id"],
+                "summary": item["summary"],
+                "location": item["location"],
+                // All-day events only have a date
+                "all_day": item["start"]["dateTime"].is_null(),
+                "start": if item["start"]["dateTime"].is_null() { &item["start"]["date"] } else { &item["start"]["dateTime"] },
+                "end": if item["end"]["dateTime"].is_null() { &item["end"]["date"] } else { &item["end"]["dateTime"] },
+            }))
+            .collect();
+        Ok(ProviderData { value: Value::Array(events), ttl: self.ttl })
+    }
+}
+
+impl DataProvider for GoogleCalendarProvider {
+    fn fetch(&self) -> anyhow::Result<ProviderData> {
+        let api_key = self.secrets.get(secrets::PROVIDER_SECRETS, &self.api_key_secret)?
+            .ok_or(anyhow!("Secret {} of {} is not set", self.api_key_secret, secrets::PROVIDER_SECRETS))?;
+        self.fetch_events(&api_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::tests::serve_json;
+
+    fn test_secrets() -> Arc<Secrets> {
+        Arc::new(Secrets::new(std::env::temp_dir().join("nemoscene-calendar-test-secrets")))
+    }
+
+    #[test]
+    fn api_key_is_not_accepted_in_the_configuration() {
+        let table = "api_key = \"secret\"\ncalendar_id = \"holidays\"".parse::<Table>().unwrap();
+        assert!(GoogleCalendarProvider::from_table(&table, test_secrets()).is_err());
+    }
+
+    #[test]
+    fn reads_events_from_the_calendar_api() {
+        let (url, server) = serve_json(r#"{"items":[
+            {"id":"a","summary":"New Year","start":{"date":"2027-01-01"},"end":{"date":"2027-01-02"}},
+            {"id":"b","summary":"Standup","location":"Office","start":{"dateTime":"2027-01-04T09:00:00Z"},"end":{"dateTime":"2027-01-04T09:15:00Z"}}
+        ]}"#, "");
+        let table = format!("base_url = \"{}/\"\napi_key_secret = \"google\"\ncalendar_id = \"en#holiday\"\nttl = 120", url).parse::<Table>().unwrap();
+        let data = GoogleCalendarProvider::from_table(&table, test_secrets()).unwrap().fetch_events("k3y").unwrap();
+        assert_eq!(data.ttl, Duration::from_secs(120));
+        assert_eq!(data.value, json!([
+            { "id": "a", "summary": "New Year", "location": null, "all_day": true, "start": "2027-01-01", "end": "2027-01-02" },
+            { "id": "b", "summary": "Standup", "location": "Office", "all_day": false, "start": "2027-01-04T09:00:00Z", "end": "2027-01-04T09:15:00Z" },
+        ]));
+        let target = server.join().unwrap();
+        assert!(target.starts_with("/calendar/v3/calendars/en%23holiday/events?key=k3y&"), "{}", target);
+    }
+}
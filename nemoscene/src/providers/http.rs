@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::anyhow;
+use serde_json::Value;
+use toml::Table;
+use crate::server::cache::max_age;
+use crate::providers::{DataProvider, ProviderData, DEFAULT_TTL};
+use crate::server::http::UPSTREAM_TIMEOUT;
+
+/// Fetches a JSON document from a URL. `select` is a JSON pointer to the part of the document
+/// to keep. Without a configured `ttl`, the response's `Cache-Control: max-age` is used.
+pub struct HttpProvider {
+    url: String,
+    headers: BTreeMap<String, String>,
+    select: Option<String>,
+    ttl: Option<Duration>,
+}
+
+impl HttpProvider {
+    pub fn from_table(table: &Table) -> anyhow::Result<HttpProvider> {
+        Ok(HttpProvider {
+            url: table.get("url").and_then(|u| u.as_str()).ok_or(anyhow!("Missing url"))?.to_string(),
+            headers: table.get("headers").and_then(|h| h.as_table()).map_or(BTreeMap::new(), |t| {
+                t.iter().filter_map(|(name, value)| value.as_str().map(|v| (name.clone(), v.to_string()))).collect()
+            }),
+            select: table.get("select").and_then(|s| s.as_str()).map(|s| s.to_string()),
+            ttl: table.get("ttl").and_then(|t| t.as_integer()).map(|t| Duration::from_secs(t.max(1) as u64)),
+        })
+    }
+}
+
+impl DataProvider for HttpProvider {
+    fn fetch(&self) -> anyhow::Result<ProviderData> {
+        let mut request = ureq::get(&self.url).timeout(UPSTREAM_TIMEOUT);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        let response = request.call().map_err(|e| anyhow!("Request to {} failed: {}", self.url, e))?;
+        let max_age = response.header("Cache-Control").and_then(max_age);
+        let value: Value = serde_json::from_reader(response.into_reader())?;
+        let value = match &self.select {
+            Some(pointer) => value.pointer(pointer).cloned().ok_or(anyhow!("{} not found in response", pointer))?,
+            None => value,
+        };
+        Ok(ProviderData { value, ttl: self.ttl.or(max_age).unwrap_or(DEFAULT_TTL) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::tests::serve_json;
+
+    #[test]
+    fn selects_part_of_the_response_and_uses_its_max_age() {
+        let (url, server) = serve_json(r#"{"current":{"temperature_2m":21.5},"units":"C"}"#, "Cache-Control: public, max-age=600\r\n");
+        let table = format!("url = \"{}/v1/forecast\"\nselect = \"/current\"", url).parse::<Table>().unwrap();
+        let data = HttpProvider::from_table(&table).unwrap().fetch().unwrap();
+        assert_eq!(data.value, serde_json::json!({ "temperature_2m": 21.5 }));
+        assert_eq!(data.ttl, Duration::from_secs(600));
+        assert_eq!(server.join().unwrap(), "/v1/forecast");
+    }
+
+    #[test]
+    fn missing_selection_is_an_error() {
+        let (url, server) = serve_json(r#"{"units":"C"}"#, "");
+        let table = format!("url = \"{}\"\nselect = \"/current\"\nttl = 60", url).parse::<Table>().unwrap();
+        assert!(HttpProvider::from_table(&table).unwrap().fetch().is_err());
+        server.join().unwrap();
+    }
+}
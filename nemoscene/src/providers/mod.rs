@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use toml::Table;
+use crate::background;
+use crate::cli::Arguments;
+use crate::configuration::ConfigurationRegistry;
+use crate::server::pubsub::PubSub;
+use crate::server::secrets::Secrets;
+
+pub mod google_calendar;
+pub mod http;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Delay before retrying a provider whose last fetch failed.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Fresh data from a provider, valid for `ttl`.
+pub struct ProviderData {
+    pub value: Value,
+    pub ttl: Duration,
+}
+
+/// A source of JSON data for widgets, fetched on the server instead of in every widget instance.
+pub trait DataProvider: Send + Sync {
+    fn fetch(&self) -> anyhow::Result<ProviderData>;
+}
+
+/// The last value fetched by a provider, with Unix timestamps of when it was fetched and when it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedData {
+    pub value: Value,
+    pub fetched: u64,
+    pub expires: u64,
+}
+
+impl CachedData {
+    pub fn is_fresh(&self) -> bool {
+        unix_time() < self.expires
+    }
+}
+
+struct ProviderEntry {
+    provider: Arc<dyn DataProvider>,
+    cached: Option<CachedData>,
+    next_refresh: u64,
+    refreshing: bool,
+}
+
+struct RegistryState {
+    providers: BTreeMap<String, ProviderEntry>,
+    stopped: bool,
+}
+
+/// Data providers configured in the `providers` configuration base, one table per provider:
+///
+/// ```toml
+/// [holidays]
+/// type = "google_calendar"
+/// api_key_secret = "google_api_key"
+/// calendar_id = "en.usa#holiday@group.v.calendar.google.com"
+///
+/// [weather]
+/// type = "http"
+/// url = "https://api.open-meteo.com/v1/forecast?latitude=52.52&longitude=13.41&current=temperature_2m"
+/// select = "/current"
+/// ttl = 900
+/// ```
+///
+/// Providers are refreshed in the background when their data expires. Values are cached under
+/// `cache/providers/` in the data root, so the last values are served while offline, and changes
+/// are published on the `data.<provider>` topic.
+pub struct ProviderRegistry {
+    state: Mutex<RegistryState>,
+    wakeup: Condvar,
+    pubsub: Arc<PubSub>,
+    secrets: Arc<Secrets>,
+    base_path: String,
+    cache_path: PathBuf,
+}
+
+impl ProviderRegistry {
+    pub fn new(pubsub: Arc<PubSub>, secrets: Arc<Secrets>, arguments: &Arguments) -> ProviderRegistry {
+        ProviderRegistry {
+            state: Mutex::new(RegistryState { providers: BTreeMap::new(), stopped: false }),
+            wakeup: Condvar::new(),
+            pubsub,
+            secrets,
+            base_path: arguments.data_path("configuration/providers"),
+            cache_path: PathBuf::from(arguments.data_path("cache/providers")),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces the providers with the ones in the `providers` configuration base.
+    pub fn load(&self, configuration: &ConfigurationRegistry) {
+        let mut providers = BTreeMap::new();
+        if let Some(base) = configuration.get_base(&self.base_path) {
+            for (name, table) in &base.properties() {
+                let provider = table.as_table().ok_or(anyhow!("Provider must be a table")).and_then(|t| self.create_provider(name, t));
+                match provider {
+                    Ok(provider) => {
+                        let cached = self.read_cache(name);
+                        providers.insert(name.clone(), ProviderEntry {
+                            provider,
+                            next_refresh: cached.as_ref().map_or(0, |c| c.expires),
+                            cached,
+                            refreshing: false,
+                        });
+                    }
+                    Err(error) => error!("Invalid data provider '{}': {}", name, error),
+                }
+            }
+        }
+        info!("Loaded {} data providers", providers.len());
+        self.state().providers = providers;
+        self.wakeup.notify_all();
+    }
+
+    fn create_provider(&self, name: &str, table: &Table) -> anyhow::Result<Arc<dyn DataProvider>> {
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            bail!("Provider names may only contain letters, digits, '_' and '-'");
+        }
+        Ok(match table.get("type").and_then(|t| t.as_str()) {
+            Some("http") => Arc::new(http::HttpProvider::from_table(table)?),
+            Some("google_calendar") => Arc::new(google_calendar::GoogleCalendarProvider::from_table(table, self.secrets.clone())?),
+            Some(other) => bail!("Unknown provider type: {}", other),
+            None => bail!("Missing provider type"),
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.state().providers.keys().cloned().collect()
+    }
+
+    /// Returns the cached data of a provider without waiting for the network: `None` if there is
+    /// no such provider, `Some(None)` if it has not been fetched successfully yet. Expired data is
+    /// returned as is and refreshed in the background once its refresh is due.
+    pub fn get(self: &Arc<Self>, name: &str) -> Option<Option<CachedData>> {
+        let mut state = self.state();
+        let entry = state.providers.get_mut(name)?;
+        if entry.next_refresh <= unix_time() {
+            self.spawn_refresh(name, entry);
+        }
+        Some(entry.cached.clone())
+    }
+
+    /// Starts refreshing a provider in the background, even if its data is still fresh.
+    /// Returns `false` if there is no such provider.
+    pub fn start_refresh(self: &Arc<Self>, name: &str) -> bool {
+        let mut state = self.state();
+        match state.providers.get_mut(name) {
+            Some(entry) => {
+                self.spawn_refresh(name, entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fetches on its own thread, unless a refresh of the provider is already running.
+    /// The caller holds the state lock, so two refreshes can never start at once.
+    fn spawn_refresh(self: &Arc<Self>, name: &str, entry: &mut ProviderEntry) {
+        if entry.refreshing {
+            return;
+        }
+        entry.refreshing = true;
+        let registry = self.clone();
+        let (name, provider) = (name.to_string(), entry.provider.clone());
+        thread::spawn(move || registry.refresh(&name, provider));
+    }
+
+    /// Fetches fresh data from a provider, stores it and publishes it if it changed.
+    fn refresh(&self, name: &str, provider: Arc<dyn DataProvider>) {
+        let result = provider.fetch();
+        let mut state = self.state();
+        let entry = match state.providers.get_mut(name) {
+            Some(entry) if Arc::ptr_eq(&entry.provider, &provider) => entry,
+            // Reloaded while fetching, the new entry has its own refresh state
+            _ => return,
+        };
+        entry.refreshing = false;
+        let data = match result {
+            Ok(data) => data,
+            Err(error) => {
+                warn!("Cannot refresh data provider {}: {}", name, error);
+                entry.next_refresh = unix_time() + RETRY_DELAY.as_secs();
+                drop(state);
+                self.wakeup.notify_all();
+                return;
+            }
+        };
+        let now = unix_time();
+        let cached = CachedData { value: data.value, fetched: now, expires: now + data.ttl.as_secs() };
+        let changed = entry.cached.as_ref().is_none_or(|c| c.value != cached.value);
+        entry.cached = Some(cached.clone());
+        entry.next_refresh = cached.expires;
+        drop(state);
+        self.wakeup.notify_all();
+        debug!("Refreshed data provider {}", name);
+        if let Err(error) = self.write_cache(name, &cached) {
+            error!("Cannot cache data of provider {}: {}", name, error);
+        }
+        if changed {
+            self.pubsub.publish(&format!("data.{}", name), cached.value);
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        let registry = self.clone();
+        thread::spawn(move || registry.run_loop());
+    }
+
+    pub fn stop(&self) {
+        self.state().stopped = true;
+        self.wakeup.notify_all();
+    }
+
+    fn run_loop(self: Arc<Self>) {
+        background::run_loop(&self.state, &self.wakeup, |state| {
+            if state.stopped {
+                return None;
+            }
+            let now = unix_time();
+            for (name, entry) in state.providers.iter_mut() {
+                if entry.next_refresh <= now {
+                    self.spawn_refresh(name, entry);
+                }
+            }
+            Some(state.providers.values()
+                .filter(|e| !e.refreshing)
+                .map(|e| Duration::from_secs(e.next_refresh.saturating_sub(now)))
+                .min()
+                .unwrap_or(background::MAX_SLEEP))
+        });
+    }
+
+    fn read_cache(&self, name: &str) -> Option<CachedData> {
+        serde_json::from_slice(&fs::read(self.cache_path.join(format!("{}.json", name))).ok()?).ok()
+    }
+
+    fn write_cache(&self, name: &str, cached: &CachedData) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.cache_path)?;
+        fs::write(self.cache_path.join(format!("{}.json", name)), serde_json::to_vec(cached)?)?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread::JoinHandle;
+
+    /// Answers one request on a local port with a JSON body and extra header lines.
+    /// Returns the server's base URL and a handle yielding the request target it received.
+    pub(crate) fn serve_json(body: &str, headers: &str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}", body.len(), headers, body);
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            request_line.split(' ').nth(1).unwrap_or_default().to_string()
+        });
+        (url, handle)
+    }
+
+    /// Counts fetches and fails each one once the test lets it finish.
+    struct SlowProvider {
+        fetches: AtomicUsize,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl DataProvider for SlowProvider {
+        fn fetch(&self) -> anyhow::Result<ProviderData> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            let _ = self.release.lock().unwrap().recv();
+            bail!("offline")
+        }
+    }
+
+    #[test]
+    fn reads_do_not_wait_for_a_single_refresh() {
+        let (release, receiver) = mpsc::channel();
+        let provider = Arc::new(SlowProvider { fetches: AtomicUsize::new(0), release: Mutex::new(receiver) });
+        let data = std::env::temp_dir().join("nemoscene-providers-test");
+        let arguments = Arguments::parse_from(["nemoscene", "--data", data.to_str().unwrap()]);
+        let registry = Arc::new(ProviderRegistry::new(Arc::new(PubSub::new()), Arc::new(Secrets::new(data.join("secrets"))), &arguments));
+        let cached = CachedData { value: Value::from(1), fetched: 0, expires: 0 };
+        registry.state().providers.insert("slow".to_string(), ProviderEntry {
+            provider: provider.clone(),
+            cached: Some(cached),
+            next_refresh: 0,
+            refreshing: false,
+        });
+
+        for _ in 0..5 {
+            let data = registry.get("slow").unwrap().unwrap();
+            assert_eq!(data.value, Value::from(1));
+            assert!(registry.start_refresh("slow"));
+        }
+        assert!(registry.get("missing").is_none());
+        assert!(!registry.start_refresh("missing"));
+
+        release.send(()).unwrap();
+        let deadline = SystemTime::now() + Duration::from_secs(5);
+        while registry.state().providers["slow"].refreshing {
+            assert!(SystemTime::now() < deadline, "refresh did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(provider.fetches.load(Ordering::SeqCst), 1);
+        let state = registry.state();
+        let entry = &state.providers["slow"];
+        assert!(entry.next_refresh > unix_time());
+        assert_eq!(entry.cached.as_ref().unwrap().value, Value::from(1));
+    }
+}
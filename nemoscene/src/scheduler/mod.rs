@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+use chrono::{Local, SecondsFormat};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::app::Bundle;
+use crate::background;
+use crate::app::manager::AppManager;
+use crate::events::{Event, EventBus};
+use crate::server::pubsub::PubSub;
+
+pub mod sun;
+pub mod task;
+
+use task::Task;
+
+/// Outcome of the most recent run of a task. `data` holds the output, or the error message if the run failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub finished: String,
+    pub duration_ms: u64,
+    pub success: bool,
+    pub data: Value,
+}
+
+/// Scheduling state of a task, as listed by the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub bundle: String,
+    pub task: String,
+    pub next_run: Option<String>,
+    pub running: bool,
+}
+
+struct ScheduledTask {
+    bundle: String,
+    bundle_path: PathBuf,
+    name: String,
+    task: Arc<Task>,
+    next_run: Option<chrono::DateTime<Local>>,
+    running: Arc<AtomicBool>,
+}
+
+struct SchedulerState {
+    tasks: Vec<ScheduledTask>,
+    coordinates: Option<(f64, f64)>,
+    stopped: bool,
+}
+
+/// Runs the background tasks declared by bundles. Results are stored under `tasks/` in the
+/// data root and published on the `tasks.<bundle>.<task>` topic.
+pub struct Scheduler {
+    state: Mutex<SchedulerState>,
+    wakeup: Condvar,
+    results: Mutex<BTreeMap<(String, String), TaskResult>>,
+    pubsub: Arc<PubSub>,
+    events: Arc<EventBus>,
+    results_path: PathBuf,
+}
+
+impl Scheduler {
+    pub fn new(pubsub: Arc<PubSub>, events: Arc<EventBus>, results_path: PathBuf) -> Scheduler {
+        Scheduler {
+            state: Mutex::new(SchedulerState { tasks: Vec::new(), coordinates: None, stopped: false }),
+            wakeup: Condvar::new(),
+            results: Mutex::new(BTreeMap::new()),
+            pubsub,
+            events,
+            results_path,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SchedulerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces all tasks with the ones declared by the loaded bundles.
+    /// `coordinates` are the latitude and longitude used for sunrise and sunset tasks.
+    pub fn load(&self, app_manager: &AppManager, coordinates: Option<(f64, f64)>) {
+        {
+            let mut state = self.state();
+            state.tasks.clear();
+            state.coordinates = coordinates;
+        }
+        for bundle in app_manager.bundles() {
+            self.load_bundle(bundle);
+        }
+    }
+
+    pub fn load_bundle(&self, bundle: &Bundle) {
+        let mut state = self.state();
+        let now = Local::now();
+        let coordinates = state.coordinates;
+        state.tasks.retain(|t| t.bundle != bundle.uuid);
+        for (name, task) in &bundle.tasks {
+            let next_run = match task.trigger {
+                task::Trigger::Interval(_) => Some(now),
+                _ => task.next_run(now, coordinates),
+            };
+            if next_run.is_none() {
+                match task.trigger {
+                    task::Trigger::Sun { .. } if coordinates.is_none() => warn!("Task {}.{} needs latitude and longitude in the nemoscene configuration", bundle.uuid, name),
+                    _ => warn!("Task {}.{} will never run", bundle.uuid, name),
+                }
+            }
+            state.tasks.push(ScheduledTask {
+                bundle: bundle.uuid.clone(),
+                bundle_path: PathBuf::from(&bundle.base_path),
+                name: name.clone(),
+                task: Arc::new(task.clone()),
+                next_run,
+                running: Arc::new(AtomicBool::new(false)),
+            });
+        }
+        self.wakeup.notify_all();
+    }
+
+    pub fn remove_bundle(&self, uuid: &str) {
+        self.state().tasks.retain(|t| t.bundle != uuid);
+        self.wakeup.notify_all();
+    }
+
+    pub fn tasks(&self) -> Vec<TaskStatus> {
+        self.state().tasks.iter().map(|t| TaskStatus {
+            bundle: t.bundle.clone(),
+            task: t.name.clone(),
+            next_run: t.next_run.map(|n| n.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            running: t.running.load(Ordering::SeqCst),
+        }).collect()
+    }
+
+    /// The most recent result of a task, read from disk if the task has not run since startup.
+    pub fn result(&self, bundle: &str, task: &str) -> Option<TaskResult> {
+        if !self.state().tasks.iter().any(|t| t.bundle == bundle && t.name == task) {
+            return None;
+        }
+        let key = (bundle.to_string(), task.to_string());
+        if let Some(result) = self.results.lock().unwrap_or_else(PoisonError::into_inner).get(&key) {
+            return Some(result.clone());
+        }
+        let result: TaskResult = serde_json::from_slice(&fs::read(self.result_path(bundle, task)).ok()?).ok()?;
+        self.results.lock().unwrap_or_else(PoisonError::into_inner).insert(key, result.clone());
+        Some(result)
+    }
+
+    /// Runs a task immediately, outside its schedule. Returns false if the task does not exist.
+    pub fn run_now(self: &Arc<Self>, bundle: &str, task: &str) -> bool {
+        let state = self.state();
+        match state.tasks.iter().find(|t| t.bundle == bundle && t.name == task) {
+            Some(scheduled) => {
+                self.spawn_run(scheduled);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        let scheduler = self.clone();
+        thread::spawn(move || scheduler.run_loop());
+    }
+
+    pub fn stop(&self) {
+        self.state().stopped = true;
+        self.wakeup.notify_all();
+    }
+
+    fn run_loop(self: Arc<Self>) {
+        background::run_loop(&self.state, &self.wakeup, |state| {
+            if state.stopped {
+                return None;
+            }
+            let now = Local::now();
+            let coordinates = state.coordinates;
+            for scheduled in state.tasks.iter_mut() {
+                if scheduled.next_run.map_or(false, |next| next <= now) {
+                    self.spawn_run(scheduled);
+                    scheduled.next_run = scheduled.task.next_run(now, coordinates);
+                }
+            }
+            Some(state.tasks.iter()
+                .filter_map(|t| t.next_run)
+                .min()
+                .and_then(|next| (next - now).to_std().ok())
+                .unwrap_or(background::MAX_SLEEP))
+        });
+    }
+
+    /// Runs a task on its own thread, unless the previous run is still in progress.
+    fn spawn_run(self: &Arc<Self>, scheduled: &ScheduledTask) {
+        if scheduled.running.swap(true, Ordering::SeqCst) {
+            warn!("Skipping task {}.{}, the previous run has not finished", scheduled.bundle, scheduled.name);
+            return;
+        }
+        let scheduler = self.clone();
+        let (bundle, name, bundle_path) = (scheduled.bundle.clone(), scheduled.name.clone(), scheduled.bundle_path.clone());
+        let (task, running) = (scheduled.task.clone(), scheduled.running.clone());
+        thread::spawn(move || {
+            let started = Instant::now();
+            let outcome = task.run(&bundle_path);
+            running.store(false, Ordering::SeqCst);
+            let result = TaskResult {
+                finished: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+                duration_ms: started.elapsed().as_millis() as u64,
+                success: outcome.is_ok(),
+                data: outcome.unwrap_or_else(|e| Value::String(e.to_string())),
+            };
+            if result.success {
+                info!("Task {}.{} finished in {} ms", bundle, name, result.duration_ms);
+            } else {
+                warn!("Task {}.{} failed: {}", bundle, name, result.data);
+            }
+            scheduler.store_result(&bundle, &name, result);
+        });
+    }
+
+    fn result_path(&self, bundle: &str, task: &str) -> PathBuf {
+        self.results_path.join(bundle).join(format!("{}.json", task))
+    }
+
+    fn store_result(&self, bundle: &str, task: &str, result: TaskResult) {
+        let path = self.result_path(bundle, task);
+        let written = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, serde_json::to_vec(&result).unwrap_or_default()));
+        if let Err(e) = written {
+            error!("Cannot store result of task {}.{}: {}", bundle, task, e);
+        }
+        let success = result.success;
+        if let Ok(data) = serde_json::to_value(&result) {
+            self.pubsub.publish(&format!("tasks.{}.{}", bundle, task), data);
+        }
+        self.results.lock().unwrap_or_else(PoisonError::into_inner).insert((bundle.to_string(), task.to_string()), result);
+        self.events.publish(Event::TaskCompleted { bundle: bundle.to_string(), task: task.to_string(), success });
+    }
+}
@@ -0,0 +1,40 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+/// Days between the Unix epoch and the J2000 epoch (2000-01-01 12:00 UTC) at noon.
+const J2000_UNIX_DAYS: f64 = 10957.5;
+
+/// Solar elevation at sunrise and sunset, accounting for refraction and the sun's radius.
+const HORIZON_ELEVATION: f64 = -0.833;
+
+const AXIAL_TILT: f64 = 23.4397;
+
+/// Sunrise and sunset on `date` at the given coordinates, using the NOAA sunrise equation.
+/// Longitude is positive east of Greenwich. Returns `None` during polar day and polar night.
+pub fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()).num_days() as f64;
+    let mean_solar_time = days - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_time).rem_euclid(360.0);
+    let center = 1.9148 * sin(anomaly) + 0.02 * sin(2.0 * anomaly) + 0.0003 * sin(3.0 * anomaly);
+    let ecliptic_longitude = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit = mean_solar_time + 0.0053 * sin(anomaly) - 0.0069 * sin(2.0 * ecliptic_longitude);
+    let declination = (sin(ecliptic_longitude) * sin(AXIAL_TILT)).asin().to_degrees();
+    let hour_angle = (sin(HORIZON_ELEVATION) - sin(latitude) * sin(declination)) / (cos(latitude) * cos(declination));
+    if !(-1.0..=1.0).contains(&hour_angle) {
+        return None;
+    }
+    let hour_angle = hour_angle.acos().to_degrees() / 360.0;
+    Some((to_utc(transit - hour_angle)?, to_utc(transit + hour_angle)?))
+}
+
+fn sin(degrees: f64) -> f64 {
+    degrees.to_radians().sin()
+}
+
+fn cos(degrees: f64) -> f64 {
+    degrees.to_radians().cos()
+}
+
+/// Converts days since J2000 to a UTC timestamp.
+fn to_utc(days: f64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(((days + J2000_UNIX_DAYS) * 86400.0).round() as i64, 0).single()
+}
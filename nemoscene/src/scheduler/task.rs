@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Days, Local, Utc};
+use serde_json::Value;
+use toml::Table;
+use crate::scheduler::sun;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/// When a task runs.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    Cron(cron::Schedule),
+    /// Runs once when loaded, then after every interval.
+    Interval(Duration),
+    /// Runs at sunrise or sunset at the configured coordinates, shifted by `offset` seconds.
+    Sun { event: SunEvent, offset: i64 },
+}
+
+/// What a task does. The output is stored as JSON if it parses as JSON, otherwise as a string.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Fetch { url: String, method: String, headers: BTreeMap<String, String>, body: Option<String> },
+    Command { program: String, arguments: Vec<String> },
+    /// An executable file inside the bundle, run from the bundle folder.
+    Script { path: PathBuf },
+}
+
+/// A background task declared in the `[tasks.<name>]` tables of a bundle manifest.
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub trigger: Trigger,
+    pub action: Action,
+    pub timeout: Duration,
+}
+
+/// Parses a cron expression. The usual five fields (minute, hour, day of month, month,
+/// day of week) run at second 0; six or seven fields start with seconds and may end with
+/// a year. Days of week are best given by name (`MON-FRI`), numbers count from 1 = Sunday.
+fn parse_cron(expression: &str) -> anyhow::Result<cron::Schedule> {
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {}", expression.trim()),
+        6 | 7 => expression.trim().to_string(),
+        fields => bail!("Invalid cron expression '{}': expected 5 fields (minute hour day month weekday) or 6-7 fields starting with seconds, got {}", expression, fields),
+    };
+    cron::Schedule::from_str(&expression).map_err(|e| anyhow!("Invalid cron expression '{}': {}", expression, e))
+}
+
+impl Task {
+    pub fn from_table(bundle_path: &Path, table: &Table) -> anyhow::Result<Task> {
+        let triggers = ["cron", "interval", "sun"].iter().filter(|k| table.contains_key(**k)).count();
+        if triggers != 1 {
+            bail!("A task needs exactly one of cron, interval or sun");
+        }
+        let actions = ["fetch", "command", "script"].iter().filter(|k| table.contains_key(**k)).count();
+        if actions != 1 {
+            bail!("A task needs exactly one of fetch, command or script");
+        }
+        let trigger = if let Some(expression) = table.get("cron") {
+            let expression = expression.as_str().ok_or(anyhow!("cron must be a string"))?;
+            Trigger::Cron(parse_cron(expression)?)
+        } else if let Some(interval) = table.get("interval") {
+            match interval.as_integer() {
+                Some(seconds) if seconds > 0 => Trigger::Interval(Duration::from_secs(seconds as u64)),
+                _ => bail!("interval must be a positive number of seconds"),
+            }
+        } else {
+            let event = match table.get("sun").and_then(|s| s.as_str()) {
+                Some("sunrise") => SunEvent::Sunrise,
+                Some("sunset") => SunEvent::Sunset,
+                _ => bail!("sun must be \"sunrise\" or \"sunset\""),
+            };
+            Trigger::Sun { event, offset: table.get("offset").and_then(|o| o.as_integer()).unwrap_or(0) }
+        };
+        let action = if let Some(url) = table.get("fetch") {
+            Action::Fetch {
+                url: url.as_str().ok_or(anyhow!("fetch must be a URL"))?.to_string(),
+                method: table.get("method").and_then(|m| m.as_str()).unwrap_or("GET").to_ascii_uppercase(),
+                headers: table.get("headers").and_then(|h| h.as_table()).map_or(BTreeMap::new(), |t| {
+                    t.iter().filter_map(|(name, value)| value.as_str().map(|v| (name.clone(), v.to_string()))).collect()
+                }),
+                body: table.get("body").and_then(|b| b.as_str()).map(|b| b.to_string()),
+            }
+        } else if let Some(command) = table.get("command") {
+            let mut command = command.as_array().ok_or(anyhow!("command must be an array of strings"))?.iter()
+                .map(|a| a.as_str().map(|a| a.to_string()).ok_or(anyhow!("command must be an array of strings")))
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            if command.is_empty() {
+                bail!("command must not be empty");
+            }
+            let program = command.remove(0);
+            Action::Command { program, arguments: command }
+        } else {
+            let script = PathBuf::from(table.get("script").and_then(|s| s.as_str()).ok_or(anyhow!("script must be a path"))?);
+            if !script.components().all(|c| matches!(c, Component::Normal(_))) {
+                bail!("script must be a path inside the bundle");
+            }
+            Action::Script { path: bundle_path.join(script) }
+        };
+        let timeout = table.get("timeout").and_then(|t| t.as_integer()).map_or(DEFAULT_TIMEOUT, |t| Duration::from_secs(t.max(1) as u64));
+        Ok(Task { trigger, action, timeout })
+    }
+
+    /// The first time after `now` the task is due, or `None` if it never is.
+    pub fn next_run(&self, now: DateTime<Local>, coordinates: Option<(f64, f64)>) -> Option<DateTime<Local>> {
+        match &self.trigger {
+            Trigger::Cron(schedule) => schedule.after(&now).next(),
+            Trigger::Interval(interval) => Some(now + chrono::Duration::from_std(*interval).ok()?),
+            Trigger::Sun { event, offset } => {
+                let (latitude, longitude) = coordinates?;
+                let today = now.with_timezone(&Utc).date_naive();
+                // Around the poles there can be no sunrise for months, so look ahead a full year
+                (0..=366).filter_map(|day| today.checked_sub_days(Days::new(1))?.checked_add_days(Days::new(day)))
+                    .filter_map(|date| sun::sun_times(date, latitude, longitude))
+                    .map(|(sunrise, sunset)| if *event == SunEvent::Sunrise { sunrise } else { sunset })
+                    .map(|time| (time + chrono::Duration::seconds(*offset)).with_timezone(&Local))
+                    .find(|time| *time > now)
+            }
+        }
+    }
+
+    pub fn run(&self, bundle_path: &Path) -> anyhow::Result<Value> {
+        match &self.action {
+            Action::Fetch { url, method, headers, body } => {
+                let mut request = ureq::request(method, url).timeout(self.timeout);
+                for (name, value) in headers {
+                    request = request.set(name, value);
+                }
+                let response = match body {
+                    Some(body) => request.send_string(body),
+                    None => request.call(),
+                }.map_err(|e| anyhow!("Request to {} failed: {}", url, e))?;
+                Ok(parse_output(response.into_string()?))
+            }
+            Action::Command { program, arguments } => {
+                let mut command = Command::new(program);
+                command.args(arguments).current_dir(bundle_path);
+                self.run_process(command)
+            }
+            Action::Script { path } => {
+                let mut command = Command::new(path);
+                command.current_dir(bundle_path);
+                self.run_process(command)
+            }
+        }
+    }
+
+    /// Runs a process and returns its standard output. The process is killed once the timeout expires.
+    fn run_process(&self, mut command: Command) -> anyhow::Result<Value> {
+        let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        // The pipes are drained while waiting, so a chatty process cannot block on a full pipe
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("Timed out after {} seconds", self.timeout.as_secs());
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        let stdout = stdout.join().map_err(|_| anyhow!("Cannot read output"))?;
+        let stderr = stderr.join().map_err(|_| anyhow!("Cannot read output"))?;
+        if !status.success() {
+            bail!("Exited with {}: {}", status, stderr.trim());
+        }
+        Ok(parse_output(stdout))
+    }
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        String::from_utf8_lossy(&output).to_string()
+    })
+}
+
+fn parse_output(output: String) -> Value {
+    serde_json::from_str(&output).unwrap_or_else(|_| Value::String(output.trim_end().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Timelike, Utc};
+    use super::*;
+
+    #[test]
+    fn five_field_cron_expressions_run_at_second_zero() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 7, 59, 30).unwrap();
+        let next = parse_cron("0 8 * * *").unwrap().after(&start).next().unwrap();
+        assert_eq!((next.hour(), next.minute(), next.second()), (8, 0, 0));
+        let next = parse_cron("*/15 * * * *").unwrap().after(&start).next().unwrap();
+        assert_eq!((next.hour(), next.minute(), next.second()), (8, 0, 0));
+    }
+
+    #[test]
+    fn cron_expressions_with_seconds_are_kept() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 7, 59, 30).unwrap();
+        let next = parse_cron("45 59 7 * * *").unwrap().after(&start).next().unwrap();
+        assert_eq!((next.hour(), next.minute(), next.second()), (7, 59, 45));
+        assert!(parse_cron("0 0 8 * * * 2030").is_ok());
+    }
+
+    #[test]
+    fn cron_expressions_with_wrong_field_count_are_rejected() {
+        assert!(parse_cron("").is_err());
+        assert!(parse_cron("* * * *").is_err());
+        assert!(parse_cron("0 0 0 1 1 * 2030 extra").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+"use strict";
+
+// Preview scale: widget positions and sizes are stored in units of 1/1000 of the screen.
+const PREVIEW_WIDTH = 400;
+const LAYOUT_UNITS = 1000;
+
+let logSequence = 0;
+let logTimer = null;
+
+async function api(method, path, body) {
+    let options = {method: method, credentials: "same-origin", headers: {}};
+    if (body !== undefined) {
+        options.headers["Content-Type"] = "application/json";
+        options.body = JSON.stringify(body);
+    }
+    let response = await fetch(path, options);
+    if (response.status === 401) {
+        showLogin();
+        throw new Error("Authentication required");
+    }
+    if (!response.ok) {
+        throw new Error(await response.text() || response.statusText);
+    }
+    let type = response.headers.get("Content-Type") || "";
+    return type.startsWith("application/json") ? response.json() : null;
+}
+
+function showLogin() {
+    document.getElementById("login").hidden = false;
+    document.querySelector("main").hidden = true;
+}
+
+function showSection(name) {
+    for (const section of document.querySelectorAll("main > section")) {
+        section.hidden = section.id !== name;
+    }
+    clearInterval(logTimer);
+    if (name === "bundles") loadBundles();
+    if (name === "configuration") loadBases();
+    if (name === "layout") loadLayout();
+    if (name === "logs") {
+        loadLogs();
+        logTimer = setInterval(loadLogs, 2000);
+    }
+}
+
+async function loadBundles() {
+    let list = document.getElementById("bundle-list");
+    list.replaceChildren();
+    for (const uuid of await api("GET", "/admin/bundles")) {
+        let item = document.createElement("li");
+        item.textContent = uuid;
+        let unload = document.createElement("button");
+        unload.textContent = "Unload";
+        unload.onclick = async () => {
+            await api("DELETE", "/admin/bundles/" + encodeURIComponent(uuid));
+            loadBundles();
+        };
+        item.appendChild(unload);
+        list.appendChild(item);
+    }
+}
+
+async function loadBases() {
+    let select = document.getElementById("base-select");
+    let selected = select.value;
+    select.replaceChildren();
+    for (const path of await api("GET", "/admin/configuration")) {
+        select.add(new Option(path, path));
+    }
+    if (selected) select.value = selected;
+    loadBase(select.value);
+}
+
+function inputFor(type, value) {
+    let input;
+    switch (type) {
+        case "boolean":
+            input = document.createElement("input");
+            input.type = "checkbox";
+            input.checked = value;
+            break;
+        case "integer":
+        case "float":
+            input = document.createElement("input");
+            input.type = "number";
+            input.step = type === "integer" ? "1" : "any";
+            input.value = value;
+            break;
+        case "string":
+            input = document.createElement("input");
+            input.value = value;
+            break;
+        default:
+            input = document.createElement("textarea");
+            input.value = JSON.stringify(value, null, 2);
+    }
+    input.dataset.type = type;
+    return input;
+}
+
+function valueOf(input) {
+    switch (input.dataset.type) {
+        case "boolean": return input.checked;
+        case "integer": return parseInt(input.value, 10);
+        case "float": return parseFloat(input.value);
+        case "string": return input.value;
+        default: return JSON.parse(input.value);
+    }
+}
+
+async function loadBase(path) {
+    let form = document.getElementById("base-form");
+    form.replaceChildren();
+    document.getElementById("base-status").textContent = "";
+    if (!path) return;
+    let base = await api("GET", "/admin/configuration/" + path);
+    for (const key of Object.keys(base.schema)) {
+        let label = document.createElement("label");
+        label.textContent = key;
+        let input = inputFor(base.schema[key].type, base.values[key]);
+        input.name = key;
+        form.append(label, input);
+    }
+    let save = document.createElement("button");
+    save.type = "submit";
+    save.textContent = "Save";
+    form.append(document.createElement("span"), save);
+}
+
+async function saveBase(event) {
+    event.preventDefault();
+    let status = document.getElementById("base-status");
+    let values = {};
+    try {
+        for (const input of event.target.querySelectorAll("[name]")) {
+            values[input.name] = valueOf(input);
+        }
+        await api("PATCH", "/admin/configuration/" + document.getElementById("base-select").value, values);
+        status.className = "status";
+        status.textContent = "Saved";
+    } catch (error) {
+        status.className = "error";
+        status.textContent = error.message;
+    }
+}
+
+async function loadLayout() {
+    let preview = document.getElementById("preview");
+    preview.replaceChildren();
+    let dashboard = (await api("GET", "/admin/configuration/configuration/dashboard")).values;
+    let scale = PREVIEW_WIDTH / dashboard.screen_width;
+    preview.style.width = PREVIEW_WIDTH + "px";
+    preview.style.height = dashboard.screen_height * scale + "px";
+    let unitX = dashboard.screen_width * scale / LAYOUT_UNITS;
+    let unitY = dashboard.screen_height * scale / LAYOUT_UNITS;
+    let paths = (await api("GET", "/admin/configuration")).filter(p => p.startsWith("configuration/widgets/"));
+    for (const path of paths) {
+        let widget = (await api("GET", "/admin/configuration/" + path)).values;
+        let element = document.createElement("div");
+        element.className = "widget";
+        element.textContent = widget.uuid;
+        element.style.left = widget.position_x * unitX + "px";
+        element.style.top = widget.position_y * unitY + "px";
+        element.style.width = widget.width * unitX + "px";
+        element.style.height = widget.height * unitY + "px";
+        makeDraggable(element, async (left, top) => {
+            await api("PATCH", "/admin/configuration/" + path, {
+                position_x: Math.round(left / unitX),
+                position_y: Math.round(top / unitY),
+            });
+        });
+        preview.appendChild(element);
+    }
+}
+
+function makeDraggable(element, onDrop) {
+    element.onpointerdown = (start) => {
+        element.setPointerCapture(start.pointerId);
+        let offsetX = start.clientX - element.offsetLeft;
+        let offsetY = start.clientY - element.offsetTop;
+        element.onpointermove = (move) => {
+            element.style.left = Math.max(0, move.clientX - offsetX) + "px";
+            element.style.top = Math.max(0, move.clientY - offsetY) + "px";
+        };
+        element.onpointerup = () => {
+            element.onpointermove = null;
+            element.onpointerup = null;
+            onDrop(element.offsetLeft, element.offsetTop);
+        };
+    };
+}
+
+async function loadLogs() {
+    let output = document.getElementById("log-output");
+    let atBottom = output.scrollTop + output.clientHeight >= output.scrollHeight - 4;
+    for (const entry of await api("GET", "/admin/logs?since=" + logSequence)) {
+        output.append(entry.line + "\n");
+        logSequence = entry.sequence + 1;
+    }
+    if (atBottom) output.scrollTop = output.scrollHeight;
+}
+
+async function login(event) {
+    event.preventDefault();
+    let password = event.target.elements.password.value;
+    let response = await fetch("/auth/login", {
+        method: "POST",
+        credentials: "same-origin",
+        headers: {"Content-Type": "application/json"},
+        body: JSON.stringify({password: password}),
+    });
+    if (response.ok) {
+        document.getElementById("login").hidden = true;
+        document.querySelector("main").hidden = false;
+        showSection("bundles");
+    } else {
+        document.getElementById("login-error").textContent = "Wrong password";
+    }
+}
+
+for (const button of document.querySelectorAll("nav button")) {
+    button.onclick = () => showSection(button.dataset.section);
+}
+document.getElementById("reload").onclick = () => api("POST", "/admin/reload");
+document.getElementById("base-select").onchange = (event) => loadBase(event.target.value);
+document.getElementById("base-form").onsubmit = saveBase;
+document.getElementById("login-form").onsubmit = login;
+
+document.querySelector("main").hidden = false;
+showSection("bundles");
@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use serde_json::{json, Map};
+use toml::Value;
+use crate::dashboard::{Dashboard, DashboardMessage};
+use crate::events::Event;
+use crate::logging;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse};
+use crate::server::router::Router;
+use crate::system_state::SystemState;
+
+const INDEX_HTML: &str = include_str!("index.html");
+const ADMIN_JS: &str = include_str!("admin.js");
+const ADMIN_CSS: &str = include_str!("admin.css");
+
+/// Keys that hold credentials. They are neither shown nor editable in the console.
+const HIDDEN_KEYS: [&str; 2] = ["admin_password_hash", "device_tokens"];
+
+/// The admin console and the endpoints it uses. Authentication is enforced by the
+/// router's `AuthMiddleware`.
+pub fn register_routes(router: &mut Router) {
+    router
+        .get("/admin", |_, _| Ok(HttpResponse::ok("text/html; charset=utf-8", INDEX_HTML.as_bytes().to_vec())))
+        .get("/admin/assets/:file", serve_asset)
+        .get("/admin/configuration", list_configuration)
+        .get("/admin/configuration/*base", get_configuration)
+        .patch("/admin/configuration/*base", patch_configuration)
+        .get("/admin/logs", get_logs)
+        .get("/admin/secrets", list_secrets)
+        .put("/admin/secrets/:uuid/:name", put_secret)
+        .delete("/admin/secrets/:uuid/:name", delete_secret)
+        .post("/admin/reload", reload);
+}
+
+fn serve_asset(_system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    match request.route_parameter("file").unwrap().as_str() {
+        "admin.js" => Ok(HttpResponse::ok("text/javascript; charset=utf-8", ADMIN_JS.as_bytes().to_vec())),
+        "admin.css" => Ok(HttpResponse::ok("text/css; charset=utf-8", ADMIN_CSS.as_bytes().to_vec())),
+        file => Err(HttpError::NotFound(format!("File not found: {}", file)).into()),
+    }
+}
+
+/// Configuration bases are addressed by their path relative to the data root,
+/// e.g. `configuration/widgets/clock`.
+fn relative_path(system_state: &SystemState, path: &str) -> String {
+    let root = system_state.arguments.data_path("");
+    path.strip_prefix(&root).unwrap_or(path).trim_start_matches('/').to_string()
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        Value::Boolean(_) => "boolean",
+        Value::Datetime(_) => "datetime",
+        Value::Array(_) => "array",
+        Value::Table(_) => "table",
+    }
+}
+
+fn list_configuration(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let paths: Vec<String> = system_state.configuration().paths().map(|p| relative_path(system_state, p)).collect();
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&paths)?))
+}
+
+/// Returns the values of a base with a schema describing the type of each value.
+fn get_configuration(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let base_path = request.route_parameter("base").unwrap();
+    let configuration = system_state.configuration();
+    let base = configuration.get_base(&system_state.arguments.data_path(base_path))
+        .ok_or(HttpError::NotFound(format!("Invalid configuration base: {}", base_path)))?;
+    let mut schema = Map::new();
+    let mut values = Map::new();
+    for (key, value) in base.properties().iter().filter(|(k, _)| !HIDDEN_KEYS.contains(&k.as_str())) {
+        schema.insert(key.clone(), json!({ "type": type_name(value) }));
+        values.insert(key.clone(), serde_json::to_value(value)?);
+    }
+    Ok(HttpResponse::ok("application/json", json!({
+        "path": base_path,
+        "schema": schema,
+        "values": values,
+    }).to_string().into_bytes()))
+}
+
+fn patch_configuration(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let values = request.json::<Map<String, serde_json::Value>>()?;
+    update_configuration(system_state, request.route_parameter("base").unwrap(), values)?;
+    Ok(HttpResponse::new(204))
+}
+
+/// Updates values of a base from a JSON object.
+/// Shared by the admin console and the control socket.
+pub fn update_configuration(system_state: &SystemState, base_path: &str, values: Map<String, serde_json::Value>) -> anyhow::Result<()> {
+    let updates = values.into_iter()
+        .map(|(key, value)| {
+            let value = Value::deserialize(value).map_err(|e| HttpError::BadRequest(format!("Invalid value for {}: {}", key, e)))?;
+            Ok((key, Some(value)))
+        })
+        .collect::<anyhow::Result<Vec<(String, Option<Value>)>>>()?;
+    write_configuration(system_state, base_path, updates)
+}
+
+/// Sets keys of a base, or removes those without a value. Every configuration write goes through here:
+/// credentials cannot be changed, existing keys keep their type, and changes to widget bases
+/// are applied to the dashboard immediately.
+pub fn write_configuration(system_state: &SystemState, base_path: &str, updates: Vec<(String, Option<Value>)>) -> anyhow::Result<()> {
+    let path = system_state.arguments.data_path(base_path);
+    let widget = {
+        let mut configuration = system_state.configuration_mut();
+        let base = configuration.get_base_mut(&path)
+            .ok_or(HttpError::NotFound(format!("Invalid configuration base: {}", base_path)))?;
+        for (key, value) in &updates {
+            if HIDDEN_KEYS.contains(&key.as_str()) {
+                return Err(HttpError::BadRequest(format!("{} cannot be changed here", key)).into());
+            }
+            match (base.get(key), value) {
+                (Some(current), Some(value)) if type_name(current) != type_name(value) => {
+                    return Err(HttpError::BadRequest(format!("{} must be of type {}", key, type_name(current))).into());
+                }
+                (None, None) => return Err(HttpError::NotFound(format!("Invalid configuration key: {}", key)).into()),
+                (Some(_), None) if base.is_override_only(key) => {
+                    return Err(HttpError::BadRequest(format!("{} is only overridden for this instance and cannot be removed", key)).into());
+                }
+                _ => {}
+            }
+        }
+        for (key, value) in &updates {
+            match value {
+                Some(value) => base.set(key, value.clone())?,
+                None => { base.remove(key)?; }
+            }
+        }
+        if base_path.starts_with("configuration/widgets/") {
+            Dashboard::load_widget(base).ok()
+        } else {
+            None
+        }
+    };
+    if let Some(widget) = widget {
+        system_state.dashboard.send_message(DashboardMessage::UpdateView(widget))?;
+    }
+    let key = match updates.as_slice() {
+        [(key, _)] => Some(key.clone()),
+        _ => None,
+    };
+    system_state.events.publish(Event::ConfigurationChanged { base: path, key });
+    Ok(())
+}
+
+fn get_logs(_system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let since = request.get_parameter("since").and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+    let filter = request.get_parameter("filter");
+    let lines: Vec<serde_json::Value> = logging::recent_lines(since).into_iter()
+        .filter(|(_, line)| filter.as_ref().map_or(true, |f| line.contains(f.as_str())))
+        .map(|(sequence, line)| json!({ "sequence": sequence, "line": line }))
+        .collect();
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&lines)?))
+}
+
+/// Secrets are write-only: only their names are listed.
+fn list_secrets(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&system_state.secrets.names()?)?))
+}
+
+fn put_secret(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let value = if request.is_json() {
+        request.json::<String>()?
+    } else {
+        request.post_parameter("value").ok_or(HttpError::BadRequest(String::from("Missing value")))?.clone()
+    };
+    system_state.secrets.set(request.route_parameter("uuid").unwrap(), request.route_parameter("name").unwrap(), &value)?;
+    Ok(HttpResponse::new(204))
+}
+
+fn delete_secret(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let (uuid, name) = (request.route_parameter("uuid").unwrap(), request.route_parameter("name").unwrap());
+    if !system_state.secrets.remove(uuid, name)? {
+        return Err(HttpError::NotFound(format!("Secret not found: {}.{}", uuid, name)).into());
+    }
+    Ok(HttpResponse::new(204))
+}
+
+fn reload(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    system_state.reload();
+    Ok(HttpResponse::new(204))
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toml::{Table, Value};
+use crate::server::admin;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse};
+use crate::server::router::Router;
+use crate::events::Event;
+use crate::providers::CachedData;
+use crate::system_state::SystemState;
+
+/// REST-style endpoints for bundle configuration and bundle administration.
+/// Values are exchanged as JSON. Requests send either a JSON body or form fields holding JSON values.
+pub fn register_routes(router: &mut Router) {
+    router
+        .get("/config/:uuid/:base", get_base)
+        .patch("/config/:uuid/:base", patch_base)
+        .get("/config/:uuid/:base/:key", get_value)
+        .put("/config/:uuid/:base/:key", put_value)
+        .delete("/config/:uuid/:base/:key", delete_value)
+        .get("/admin/bundles", list_bundles)
+        .delete("/admin/bundles/:uuid", unload_bundle)
+        .get("/tasks", list_tasks)
+        .get("/tasks/:uuid/:task", get_task_result)
+        .post("/tasks/:uuid/:task", run_task)
+        .get("/data", list_providers)
+        .get("/data/:provider", get_data)
+        .post("/data/:provider/refresh", refresh_data);
+}
+
+/// The base of a request, relative to the data directory.
+fn base_path(request: &HttpRequest) -> String {
+    let uuid = request.route_parameter("uuid").unwrap();
+    let base = request.route_parameter("base").unwrap();
+    format!("bundles/{}/config/{}", uuid, base)
+}
+
+fn parse_value(json: &str) -> anyhow::Result<Value> {
+    serde_json::from_str::<Value>(json).map_err(|e| HttpError::BadRequest(format!("Invalid configuration value: {}", e)).into())
+}
+
+fn get_base(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let path = system_state.arguments.data_path(&base_path(request));
+    let configuration = system_state.configuration();
+    let base = configuration.get_base(&path).ok_or(HttpError::NotFound(format!("Invalid configuration base: {}", path)))?;
+    Ok(HttpResponse::ok("application/json", base.to_json()?))
+}
+
+fn patch_base(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let values = if request.is_json() {
+        request.json::<Table>()?.into_iter().map(|(key, value)| (key, Some(value))).collect()
+    } else {
+        let post = request.post.as_ref().ok_or(HttpError::BadRequest(String::from("Missing request body")))?;
+        post.iter()
+            .map(|(key, value)| Ok((key.clone(), Some(parse_value(value.as_string()?)?))))
+            .collect::<anyhow::Result<Vec<(String, Option<Value>)>>>()?
+    };
+    admin::write_configuration(system_state, &base_path(request), values)?;
+    Ok(HttpResponse::new(204))
+}
+
+fn get_value(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let path = system_state.arguments.data_path(&base_path(request));
+    let key = request.route_parameter("key").unwrap();
+    let configuration = system_state.configuration();
+    let base = configuration.get_base(&path).ok_or(HttpError::NotFound(format!("Invalid configuration base: {}", path)))?;
+    let value = base.get_json(key).ok_or(HttpError::NotFound(format!("Invalid configuration key: {}", key)))?;
+    Ok(HttpResponse::ok("application/json", value))
+}
+
+fn put_value(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let key = request.route_parameter("key").unwrap();
+    let value = if request.is_json() {
+        request.json::<Value>()?
+    } else {
+        parse_value(request.post_parameter("value").ok_or(HttpError::BadRequest(String::from("Missing value")))?)?
+    };
+    admin::write_configuration(system_state, &base_path(request), vec![(key.clone(), Some(value))])?;
+    Ok(HttpResponse::new(204))
+}
+
+fn delete_value(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let key = request.route_parameter("key").unwrap();
+    admin::write_configuration(system_state, &base_path(request), vec![(key.clone(), None)])?;
+    Ok(HttpResponse::new(204))
+}
+
+fn list_bundles(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let bundles: Vec<String> = system_state.app_manager().bundles().map(|b| b.uuid.clone()).collect();
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&bundles)?))
+}
+
+fn unload_bundle(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let uuid = request.route_parameter("uuid").unwrap();
+    let mut configuration = system_state.configuration_mut();
+    let mut app_manager = system_state.app_manager_mut();
+    if app_manager.get_bundle(uuid).is_none() {
+        return Err(HttpError::NotFound(format!("Bundle not found: {}", uuid)).into());
+    }
+    app_manager.unload_bundle(uuid, &mut configuration)?;
+    drop(app_manager);
+    drop(configuration);
+    system_state.scheduler.remove_bundle(uuid);
+    system_state.events.publish(Event::BundleUnloaded { uuid: uuid.clone() });
+    Ok(HttpResponse::new(204))
+}
+
+fn list_tasks(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&system_state.scheduler.tasks())?))
+}
+
+fn get_task_result(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let uuid = request.route_parameter("uuid").unwrap();
+    let task = request.route_parameter("task").unwrap();
+    let result = system_state.scheduler.result(uuid, task).ok_or(HttpError::NotFound(format!("No result for task {}.{}", uuid, task)))?;
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&result)?))
+}
+
+fn run_task(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let uuid = request.route_parameter("uuid").unwrap();
+    let task = request.route_parameter("task").unwrap();
+    if !system_state.scheduler.run_now(uuid, task) {
+        return Err(HttpError::NotFound(format!("Task not found: {}.{}", uuid, task)).into());
+    }
+    Ok(HttpResponse::new(202))
+}
+
+fn list_providers(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    Ok(HttpResponse::ok("application/json", serde_json::to_vec(&system_state.providers.names())?))
+}
+
+fn data_response(data: CachedData) -> anyhow::Result<HttpResponse> {
+    let fetched = UNIX_EPOCH + Duration::from_secs(data.fetched);
+    let response = HttpResponse::ok("application/json", serde_json::to_vec(&data.value)?)
+        .with_header("Last-Modified", &httpdate::fmt_http_date(fetched));
+    Ok(if data.is_fresh() {
+        let remaining = data.expires.saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        response.with_header("Cache-Control", &format!("max-age={}", remaining))
+    } else {
+        response.with_header("Cache-Control", "no-cache").with_header("Warning", "110 - \"Response is Stale\"")
+    })
+}
+
+fn get_data(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let provider = request.route_parameter("provider").unwrap();
+    match system_state.providers.get(provider) {
+        Some(Some(data)) => data_response(data),
+        Some(None) => Ok(HttpResponse::new(503)
+            .with_header("Retry-After", "5")
+            .with_header("Content-Type", "text/plain")
+            .with_body(format!("No data from provider {} yet", provider).into_bytes())),
+        None => Err(HttpError::NotFound(format!("Data provider not found: {}", provider)).into()),
+    }
+}
+
+fn refresh_data(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let provider = request.route_parameter("provider").unwrap();
+    if !system_state.providers.start_refresh(provider) {
+        return Err(HttpError::NotFound(format!("Data provider not found: {}", provider)).into());
+    }
+    Ok(HttpResponse::new(202))
+}
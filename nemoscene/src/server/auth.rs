@@ -0,0 +1,650 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use log::{info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use toml::Value;
+use crate::dashboard::DashboardMessage;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse, RequestType};
+use crate::server::router::{Middleware, Next, Router};
+use crate::system_state::SystemState;
+
+const SESSION_COOKIE: &str = "nemoscene_session";
+
+/// Holds the per-launch token of a dashboard view. Every view has its own cookie jar.
+const VIEW_COOKIE: &str = "nemoscene_view";
+
+const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+const PAIRING_CODE_LIFETIME: Duration = Duration::from_secs(120);
+
+const MAX_PAIRING_ATTEMPTS: u32 = 5;
+
+const MAX_PENDING_PAIRINGS: usize = 16;
+
+const MAX_PENDING_PAIRINGS_PER_PEER: usize = 2;
+
+/// Wrong pairing codes tolerated across all pairings before pairing is locked for `PAIRING_LOCKOUT`.
+const MAX_PAIRING_FAILURES: u32 = 20;
+
+const PAIRING_LOCKOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The delay after the first failed login of a client. It doubles with every further failure.
+const LOGIN_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Failed logins are forgotten after this long without another failure.
+const LOGIN_FAILURE_MEMORY: Duration = Duration::from_secs(30 * 60);
+
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+/// Routes remote clients can use without credentials. Entries ending in `/` match every route below them.
+/// The admin console page is public so it can show the login form.
+/// Health and metrics endpoints are public for fleet monitoring.
+const PUBLIC_ROUTES: [&str; 10] = ["/auth/login", "/auth/pair", "/auth/pair/", "/auth/view/", "/favicon.ico", "/admin", "/admin/assets/", "/healthz", "/readyz", "/metrics"];
+
+/// Routes dashboard views cannot use with their view token, only with a login session or device token.
+const ADMIN_ROUTES: [&str; 2] = ["/admin/", "/auth/password"];
+/// Routes whose second segment is a bundle uuid. Views may only use them for their own bundle.
+const BUNDLE_ROUTES: [&str; 2] = ["config", "tasks"];
+
+struct Pairing {
+    code: String,
+    expires: Instant,
+    attempts: u32,
+    peer: Option<IpAddr>,
+}
+
+/// Consecutive failures and the time of the last one.
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    fn login_backoff(&self) -> Duration {
+        LOGIN_BACKOFF.saturating_mul(1 << self.count.saturating_sub(1).min(16)).min(MAX_LOGIN_BACKOFF)
+    }
+}
+
+/// Sessions and pairing requests of remote clients, and the tokens of the dashboard's views.
+/// Login sessions and view tokens live in memory, tokens of paired devices are stored hashed
+/// in the `nemoscene` configuration base.
+pub struct Authenticator {
+    sessions: Mutex<HashMap<String, Instant>>,
+    pairings: Mutex<HashMap<String, Pairing>>,
+    /// Hashed view tokens by bundle uuid. A view gets a new token every time it is attached.
+    view_tokens: Mutex<HashMap<String, String>>,
+    login_failures: Mutex<HashMap<Option<IpAddr>, Failures>>,
+    pairing_failures: Mutex<Option<Failures>>,
+    /// Held while a password is checked, so parallel logins cannot occupy more than one core.
+    password_check: Mutex<()>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator {
+            sessions: Mutex::new(HashMap::new()),
+            pairings: Mutex::new(HashMap::new()),
+            view_tokens: Mutex::new(HashMap::new()),
+            login_failures: Mutex::new(HashMap::new()),
+            pairing_failures: Mutex::new(None),
+            password_check: Mutex::new(()),
+        }
+    }
+
+    /// Creates the token a dashboard view uses to authenticate as the given bundle,
+    /// replacing the token of an earlier view of the same bundle.
+    pub fn issue_view_token(&self, bundle: &str) -> String {
+        let token = random_token();
+        self.view_tokens.lock().unwrap_or_else(PoisonError::into_inner).insert(bundle.to_string(), hash_token(&token));
+        token
+    }
+
+    /// The bundle a view token belongs to.
+    fn view_token_bundle(&self, token: &str) -> Option<String> {
+        let token = hash_token(token);
+        self.view_tokens.lock().unwrap_or_else(PoisonError::into_inner).iter()
+            .find(|(_, t)| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            .map(|(bundle, _)| bundle.clone())
+    }
+
+    /// The bundle of the dashboard view that sent the request, identified by its view cookie.
+    pub fn view_bundle(&self, request: &HttpRequest) -> Option<String> {
+        self.view_token_bundle(request.cookie(VIEW_COOKIE)?)
+    }
+
+    /// The bearer token or session cookie sent with a request.
+    fn request_token(request: &HttpRequest) -> Option<&str> {
+        request.header_value("Authorization")
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .map(|t| t.trim())
+            .or_else(|| request.cookie(SESSION_COOKIE))
+    }
+
+    /// Local dashboard views are authenticated by their view cookie, except on admin routes
+    /// and on routes of other bundles. Everyone else needs a login session or a device token.
+    pub fn is_authenticated(&self, system_state: &SystemState, request: &HttpRequest) -> bool {
+        if is_local(request) && self.view_bundle(request).is_some_and(|bundle| view_may_access(&bundle, &request.route)) {
+            return true;
+        }
+        let token = match Self::request_token(request) {
+            Some(token) => hash_token(token),
+            None => return false,
+        };
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        sessions.retain(|_, expires| *expires > Instant::now());
+        if sessions.contains_key(&token) {
+            return true;
+        }
+        drop(sessions);
+        device_tokens(system_state).iter().any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+    }
+
+    fn create_session(&self, system_state: &SystemState) -> String {
+        let lifetime = nemoscene_setting(system_state, "session_lifetime").and_then(|v| v.as_integer())
+            .map_or(DEFAULT_SESSION_LIFETIME, |hours| Duration::from_secs(hours as u64 * 60 * 60));
+        let token = random_token();
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).insert(hash_token(&token), Instant::now() + lifetime);
+        token
+    }
+
+    fn end_session(&self, token: &str) {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(&hash_token(token));
+    }
+
+    /// How long a client has to wait before its next login attempt is checked.
+    fn login_retry_after(&self, peer: Option<IpAddr>) -> Option<Duration> {
+        let mut failures = self.login_failures.lock().unwrap_or_else(PoisonError::into_inner);
+        failures.retain(|_, f| f.last.elapsed() < LOGIN_FAILURE_MEMORY);
+        let failure = failures.get(&peer)?;
+        (failure.last + failure.login_backoff()).checked_duration_since(Instant::now())
+    }
+
+    /// Checks a login password, one check at a time, and records the outcome for the client's backoff.
+    fn check_login(&self, peer: Option<IpAddr>, password: &str, hash: &str) -> bool {
+        let valid = {
+            let _check = self.password_check.lock().unwrap_or_else(PoisonError::into_inner);
+            verify_password(password, hash)
+        };
+        let mut failures = self.login_failures.lock().unwrap_or_else(PoisonError::into_inner);
+        if valid {
+            failures.remove(&peer);
+        } else {
+            let count = failures.get(&peer).map_or(0, |f| f.count);
+            failures.insert(peer, Failures { count: count + 1, last: Instant::now() });
+        }
+        valid
+    }
+
+    /// Whether too many wrong pairing codes were sent recently.
+    fn pairing_locked(&self) -> bool {
+        let failures = self.pairing_failures.lock().unwrap_or_else(PoisonError::into_inner);
+        failures.is_some_and(|f| f.count >= MAX_PAIRING_FAILURES && f.last.elapsed() < PAIRING_LOCKOUT)
+    }
+
+    fn record_pairing_failure(&self) {
+        let mut failures = self.pairing_failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = failures.filter(|f| f.last.elapsed() < PAIRING_LOCKOUT).map_or(0, |f| f.count);
+        *failures = Some(Failures { count: count + 1, last: Instant::now() });
+        if count + 1 == MAX_PAIRING_FAILURES {
+            warn!("Too many wrong pairing codes, pairing locked for {} minutes", PAIRING_LOCKOUT.as_secs() / 60);
+        }
+    }
+
+    /// Creates a pairing and returns its id and one-time code, unless pairing is locked or
+    /// too many pairings are pending, in total or for the client.
+    fn create_pairing(&self, peer: Option<IpAddr>) -> Option<(String, String)> {
+        if self.pairing_locked() {
+            return None;
+        }
+        let mut pairings = self.pairings.lock().unwrap_or_else(PoisonError::into_inner);
+        pairings.retain(|_, pairing| pairing.expires > Instant::now());
+        if pairings.len() >= MAX_PENDING_PAIRINGS || pairings.values().filter(|p| p.peer == peer).count() >= MAX_PENDING_PAIRINGS_PER_PEER {
+            return None;
+        }
+        let id = random_token();
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        pairings.insert(id.clone(), Pairing { code: code.clone(), expires: Instant::now() + PAIRING_CODE_LIFETIME, attempts: 0, peer });
+        Some((id, code))
+    }
+
+    /// Checks a pairing code. A pairing is discarded once it succeeds, expires or sees too many wrong codes.
+    fn complete_pairing(&self, id: &str, code: &str) -> bool {
+        if self.pairing_locked() {
+            return false;
+        }
+        let mut pairings = self.pairings.lock().unwrap_or_else(PoisonError::into_inner);
+        let pairing = match pairings.get_mut(id) {
+            Some(pairing) if pairing.expires > Instant::now() => pairing,
+            _ => {
+                pairings.remove(id);
+                return false;
+            }
+        };
+        if constant_time_eq(pairing.code.as_bytes(), code.as_bytes()) {
+            pairings.remove(id);
+            return true;
+        }
+        pairing.attempts += 1;
+        if pairing.attempts >= MAX_PAIRING_ATTEMPTS {
+            warn!("Too many wrong pairing codes, pairing cancelled");
+            pairings.remove(id);
+        }
+        drop(pairings);
+        self.record_pairing_failure();
+        false
+    }
+}
+
+/// Rejects requests from untrusted clients without a valid session or token.
+/// CORS preflight requests never carry credentials, so they are always let through.
+pub struct AuthMiddleware;
+
+impl Middleware for AuthMiddleware {
+    fn handle(&self, system_state: &SystemState, request: &HttpRequest, next: Next) -> anyhow::Result<HttpResponse> {
+        let public = matches_any(&PUBLIC_ROUTES, &request.route);
+        if public || request.method == RequestType::Options || system_state.auth.is_authenticated(system_state, request) {
+            return next(system_state, request);
+        }
+        Ok(HttpResponse::new(401)
+            .with_header("WWW-Authenticate", "Bearer realm=\"nemoscene\"")
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"Authentication required".to_vec()))
+    }
+}
+
+/// Rejects requests whose `Host` is not localhost, an IP address or one of the `server_names`
+/// of the `nemoscene` configuration base, so DNS rebinding cannot reach the server
+/// through a foreign host name.
+pub struct HostMiddleware;
+
+impl Middleware for HostMiddleware {
+    fn handle(&self, system_state: &SystemState, request: &HttpRequest, next: Next) -> anyhow::Result<HttpResponse> {
+        let server_names: Vec<String> = nemoscene_setting(system_state, "server_names")
+            .and_then(|v| v.as_array().map(|a| a.iter().filter_map(|n| n.as_str().map(|s| s.to_string())).collect()))
+            .unwrap_or_default();
+        match request.header_value("Host") {
+            Some(host) if is_allowed_host(host, &server_names) => next(system_state, request),
+            _ => {
+                warn!("Rejected request for host {:?} from {:?}", request.header_value("Host"), request.peer);
+                Err(HttpError::BadRequest(String::from("Unknown host")).into())
+            }
+        }
+    }
+}
+
+/// Whether a `Host` header names this server. The port is ignored.
+fn is_allowed_host(host: &str, server_names: &[String]) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((address, port)) if port.is_empty() || port.strip_prefix(':').is_some_and(is_port) => return address.parse::<IpAddr>().is_ok(),
+            _ => return false,
+        },
+        None => match host.rsplit_once(':') {
+            Some((name, port)) if is_port(port) && !name.contains(':') => name,
+            Some(_) => return false,
+            None => host,
+        },
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok()
+        || server_names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn is_port(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Entries ending in `/` match every route below them. Empty segments are ignored,
+/// like the router does, so `//admin/secrets` is still an admin route.
+fn matches_any(routes: &[&str], route: &str) -> bool {
+    let route = format!("/{}", route.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>().join("/"));
+    routes.iter().any(|r| if r.ends_with('/') { route.starts_with(r) } else { route == *r })
+}
+
+pub fn register_routes(router: &mut Router) {
+    router
+        .get("/auth/view/:token", start_view_session)
+        .post("/auth/login", login)
+        .post("/auth/logout", logout)
+        .post("/auth/pair", start_pairing)
+        .post("/auth/pair/:id", complete_pairing)
+        .put("/auth/password", set_password);
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    password: Option<String>,
+    code: Option<String>,
+}
+
+/// Reads credentials from a JSON body or form fields.
+fn credentials(request: &HttpRequest) -> anyhow::Result<Credentials> {
+    if request.is_json() {
+        return request.json::<Credentials>();
+    }
+    Ok(Credentials {
+        password: request.post_parameter("password").cloned(),
+        code: request.post_parameter("code").cloned(),
+    })
+}
+
+fn session_response(request: &HttpRequest, token: &str, max_age: Option<Duration>) -> HttpResponse {
+    let mut cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Strict", SESSION_COOKIE, token);
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+    }
+    if request.secure {
+        cookie.push_str("; Secure");
+    }
+    HttpResponse::ok("application/json", json!({ "token": token }).to_string().into_bytes())
+        .with_header("Set-Cookie", &cookie)
+}
+
+/// Entry point of a dashboard view. Stores the view token from the URL in a cookie,
+/// then redirects to `next`. Only the local views load this over loopback.
+/// Dashboard views run on this machine and talk to the server over plain HTTP on loopback.
+fn is_local(request: &HttpRequest) -> bool {
+    !request.secure && request.peer.is_some_and(|peer| peer.ip().is_loopback())
+}
+
+/// Whether the view of a bundle may use a route: never an admin route, and a bundle route only for its own bundle.
+fn view_may_access(bundle: &str, route: &str) -> bool {
+    if matches_any(&ADMIN_ROUTES, route) {
+        return false;
+    }
+    let mut parts = route.split('/').filter(|s| !s.is_empty());
+    match parts.next() {
+        Some(first) if BUNDLE_ROUTES.contains(&first) => parts.next().is_none_or(|uuid| url_escape::decode(uuid) == bundle),
+        _ => true,
+    }
+}
+
+fn start_view_session(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let token = request.route_parameter("token").unwrap();
+    if !is_local(request) || system_state.auth.view_token_bundle(token).is_none() {
+        warn!("Invalid view token from {:?}", request.peer);
+        return Ok(HttpResponse::new(403));
+    }
+    let next = request.get_parameter("next").map(|n| n.as_str()).filter(|n| is_local_path(n)).unwrap_or("/");
+    Ok(HttpResponse::redirect(next)
+        .with_header("Set-Cookie", &format!("{}={}; Path=/; HttpOnly; SameSite=Strict", VIEW_COOKIE, token)))
+}
+
+/// Only paths on this server are valid redirect targets, not `//host` or `/\\host`.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    HttpResponse::new(429)
+        .with_header("Retry-After", &retry_after.as_secs().max(1).to_string())
+        .with_header("Content-Type", "text/plain")
+        .with_body(b"Too many attempts, try again later".to_vec())
+}
+
+/// Failed logins make the client wait before its next attempt is checked, doubling the delay each time.
+fn login(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let peer = request.peer.map(|p| p.ip());
+    if let Some(retry_after) = system_state.auth.login_retry_after(peer) {
+        return Ok(too_many_requests(retry_after));
+    }
+    let password = credentials(request)?.password.ok_or(HttpError::BadRequest(String::from("Missing password")))?;
+    let hash = nemoscene_setting(system_state, "admin_password_hash").and_then(|v| v.as_str().map(|s| s.to_string()));
+    match hash {
+        Some(hash) if system_state.auth.check_login(peer, &password, &hash) => {
+            let token = system_state.auth.create_session(system_state);
+            Ok(session_response(request, &token, None))
+        }
+        _ => {
+            warn!("Failed login from {:?}", request.peer);
+            Ok(HttpResponse::new(401).with_header("WWW-Authenticate", "Bearer realm=\"nemoscene\""))
+        }
+    }
+}
+
+fn logout(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    if let Some(token) = Authenticator::request_token(request) {
+        system_state.auth.end_session(token);
+    }
+    Ok(HttpResponse::new(204).with_header("Set-Cookie", &format!("{}=; Path=/; Max-Age=0", SESSION_COOKIE)))
+}
+
+/// Starts pairing a device. The one-time code is only shown on the panel and must be
+/// sent back by the device within two minutes.
+fn start_pairing(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let (id, code) = match system_state.auth.create_pairing(request.peer.map(|p| p.ip())) {
+        Some(pairing) => pairing,
+        None => {
+            warn!("Refused pairing request from {:?}", request.peer);
+            return Ok(too_many_requests(PAIRING_CODE_LIFETIME));
+        }
+    };
+    info!("Pairing requested by {:?}", request.peer);
+    system_state.dashboard.send_message(DashboardMessage::ShowPairingCode(code, PAIRING_CODE_LIFETIME))?;
+    Ok(HttpResponse::ok("application/json", json!({
+        "pairing_id": id,
+        "expires_in": PAIRING_CODE_LIFETIME.as_secs(),
+    }).to_string().into_bytes()))
+}
+
+/// Exchanges the code shown on the panel for a device token that stays valid until revoked.
+fn complete_pairing(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let id = request.route_parameter("id").unwrap();
+    let code = credentials(request)?.code.ok_or(HttpError::BadRequest(String::from("Missing code")))?;
+    if !system_state.auth.complete_pairing(id, code.trim()) {
+        warn!("Failed pairing attempt from {:?}", request.peer);
+        return Ok(HttpResponse::new(403));
+    }
+    let token = random_token();
+    // Read and write the token list under one lock, so concurrent pairings cannot drop a token.
+    let mut configuration = system_state.configuration_mut();
+    let base = configuration.get_base_mut(&system_state.arguments.data_path("configuration/nemoscene"))
+        .ok_or(anyhow!("Missing nemoscene configuration"))?;
+    let mut tokens = token_list(base.get("device_tokens"));
+    tokens.push(hash_token(&token));
+    base.set_array("device_tokens", tokens)?;
+    info!("Paired device {:?}", request.peer);
+    Ok(session_response(request, &token, Some(Duration::from_secs(10 * 365 * 24 * 60 * 60))))
+}
+
+fn set_password(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let password = credentials(request)?.password.ok_or(HttpError::BadRequest(String::from("Missing password")))?;
+    if password.len() < 8 {
+        return Err(HttpError::BadRequest(String::from("Password must have at least 8 characters")).into());
+    }
+    let mut configuration = system_state.configuration_mut();
+    let base = configuration.get_base_mut(&system_state.arguments.data_path("configuration/nemoscene"))
+        .ok_or(anyhow!("Missing nemoscene configuration"))?;
+    base.set_str("admin_password_hash", &hash_password(&password))?;
+    Ok(HttpResponse::new(204))
+}
+
+fn nemoscene_setting(system_state: &SystemState, key: &str) -> Option<Value> {
+    let configuration = system_state.configuration();
+    configuration.get_base(&system_state.arguments.data_path("configuration/nemoscene"))?.get(key).cloned()
+}
+
+fn device_tokens(system_state: &SystemState) -> Vec<String> {
+    token_list(nemoscene_setting(system_state, "device_tokens").as_ref())
+}
+
+fn token_list(value: Option<&Value>) -> Vec<String> {
+    value.and_then(|v| v.as_array().map(|a| a.iter().filter_map(|t| t.as_str().map(|s| s.to_string())).collect()))
+        .unwrap_or_default()
+}
+
+fn random_token() -> String {
+    hex(&rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// Tokens are only ever stored hashed.
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Hashes a password as `sha256$<iterations>$<salt>$<hash>`.
+fn hash_password(password: &str) -> String {
+    let salt = hex(&rand::thread_rng().gen::<[u8; 16]>());
+    format!("sha256${}${}${}", PASSWORD_HASH_ITERATIONS, salt, stretch(password, &salt, PASSWORD_HASH_ITERATIONS))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    match parts.as_slice() {
+        ["sha256", iterations, salt, expected] => match iterations.parse::<u32>() {
+            Ok(iterations) => constant_time_eq(stretch(password, salt, iterations).as_bytes(), expected.as_bytes()),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+fn stretch(password: &str, salt: &str, iterations: u32) -> String {
+    let mut digest = Sha256::new().chain_update(salt.as_bytes()).chain_update(password.as_bytes()).finalize();
+    for _ in 1..iterations {
+        digest = Sha256::new().chain_update(digest).chain_update(salt.as_bytes()).finalize();
+    }
+    hex(&digest)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use crate::server::http::HttpLimits;
+
+    fn request(head: &str) -> HttpRequest {
+        HttpRequest::read_from(&mut format!("{}\r\n\r\n", head).as_bytes(), &HttpLimits::default()).unwrap()
+    }
+
+    #[test]
+    fn local_and_configured_hosts_are_allowed() {
+        let names = vec![String::from("panel.example")];
+        for host in ["localhost", "localhost:1337", "LOCALHOST.", "127.0.0.1:1337", "192.168.1.20", "[::1]:1337", "[fe80::1]", "panel.example:1338"] {
+            assert!(is_allowed_host(host, &names), "{}", host);
+        }
+        for host in ["", "attacker.example", "localhost.attacker.example", "localhost:", "localhost:port", "[::1", "[localhost]:1337", "::1"] {
+            assert!(!is_allowed_host(host, &names), "{}", host);
+        }
+    }
+
+    #[test]
+    fn view_tokens_identify_their_bundle() {
+        let auth = Authenticator::new();
+        let first = auth.issue_view_token("clock");
+        let weather = auth.issue_view_token("weather");
+        assert_eq!(auth.view_bundle(&request(&format!("GET / HTTP/1.1\r\nCookie: a=b; {}={}", VIEW_COOKIE, weather))).as_deref(), Some("weather"));
+        assert_eq!(auth.view_bundle(&request(&format!("GET / HTTP/1.1\r\nCookie: {}={}", VIEW_COOKIE, first))).as_deref(), Some("clock"));
+        let second = auth.issue_view_token("clock");
+        assert_eq!(auth.view_token_bundle(&first), None);
+        assert_eq!(auth.view_token_bundle(&second).as_deref(), Some("clock"));
+        assert_eq!(auth.view_bundle(&request("GET / HTTP/1.1")), None);
+        assert_eq!(auth.view_bundle(&request(&format!("GET / HTTP/1.1\r\nCookie: {}=forged", VIEW_COOKIE))), None);
+    }
+
+    #[test]
+    fn route_lists_ignore_empty_segments() {
+        assert!(matches_any(&ADMIN_ROUTES, "/admin/secrets"));
+        assert!(matches_any(&ADMIN_ROUTES, "//admin//secrets"));
+        assert!(matches_any(&ADMIN_ROUTES, "/auth/password/"));
+        assert!(!matches_any(&ADMIN_ROUTES, "/admin"));
+        assert!(!matches_any(&ADMIN_ROUTES, "/administration"));
+    }
+
+    #[test]
+    fn views_only_reach_their_own_bundle() {
+        assert!(view_may_access("clock", "/config/clock/widget"));
+        assert!(view_may_access("clock", "//tasks/%63lock/sync"));
+        assert!(view_may_access("clock", "/tasks"));
+        assert!(view_may_access("clock", "/data/weather"));
+        assert!(!view_may_access("clock", "/config/weather/widget/city"));
+        assert!(!view_may_access("clock", "/tasks//weather/sync"));
+        assert!(!view_may_access("clock", "/admin/secrets"));
+    }
+
+    #[test]
+    fn view_cookies_need_a_loopback_peer() {
+        let mut request = request("GET / HTTP/1.1");
+        assert!(!is_local(&request));
+        request.peer = Some(SocketAddr::from(([192, 168, 1, 20], 40000)));
+        assert!(!is_local(&request));
+        request.peer = Some(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        assert!(is_local(&request));
+        request.secure = true;
+        assert!(!is_local(&request));
+    }
+
+    #[test]
+    fn pending_pairings_are_limited() {
+        let auth = Authenticator::new();
+        let peer = Some(IpAddr::from([192, 168, 1, 20]));
+        assert!(auth.create_pairing(peer).is_some());
+        assert!(auth.create_pairing(peer).is_some());
+        assert!(auth.create_pairing(peer).is_none());
+        for i in 0..MAX_PENDING_PAIRINGS - 2 {
+            assert!(auth.create_pairing(Some(IpAddr::from([10, 0, 0, i as u8]))).is_some());
+        }
+        assert!(auth.create_pairing(Some(IpAddr::from([10, 0, 1, 1]))).is_none());
+    }
+
+    #[test]
+    fn wrong_pairing_codes_lock_pairing() {
+        let auth = Authenticator::new();
+        let (id, code) = auth.create_pairing(None).unwrap();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        assert!(!auth.complete_pairing(&id, wrong));
+        assert!(auth.complete_pairing(&id, &code));
+        assert!(!auth.complete_pairing(&id, &code));
+        for _ in 0..MAX_PAIRING_FAILURES / MAX_PAIRING_ATTEMPTS {
+            let (id, code) = auth.create_pairing(None).unwrap();
+            let wrong = if code == "000000" { "000001" } else { "000000" };
+            for _ in 0..MAX_PAIRING_ATTEMPTS {
+                assert!(!auth.complete_pairing(&id, wrong));
+            }
+        }
+        assert!(auth.create_pairing(None).is_none());
+    }
+
+    #[test]
+    fn failed_logins_back_off() {
+        let auth = Authenticator::new();
+        let hash = hash_password("correct horse");
+        let peer = Some(IpAddr::from([192, 168, 1, 20]));
+        assert_eq!(auth.login_retry_after(peer), None);
+        assert!(!auth.check_login(peer, "wrong", &hash));
+        assert!(auth.login_retry_after(peer).is_some_and(|d| d <= LOGIN_BACKOFF));
+        assert_eq!(auth.login_retry_after(None), None);
+        assert!(!auth.check_login(peer, "wrong", &hash));
+        assert!(auth.login_retry_after(peer).is_some_and(|d| d > LOGIN_BACKOFF));
+        assert!(auth.check_login(peer, "correct horse", &hash));
+        assert_eq!(auth.login_retry_after(peer), None);
+        let failures = Failures { count: 40, last: Instant::now() };
+        assert_eq!(failures.login_backoff(), MAX_LOGIN_BACKOFF);
+    }
+
+    #[test]
+    fn redirects_stay_on_this_server() {
+        assert!(is_local_path("/bundle/clock"));
+        assert!(!is_local_path("//attacker.example/"));
+        assert!(!is_local_path("/\\attacker.example/"));
+        assert!(!is_local_path("https://attacker.example/"));
+    }
+}
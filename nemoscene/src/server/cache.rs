@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use crate::server::compression::Encoding;
+use crate::server::http::{HttpRequest, HttpResponse};
+
+/// Memory budget of each in-memory response cache: compressed files and proxied responses.
+pub const MAX_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Content hashes of served files, keyed by path. A hash is recomputed only when
+/// the size or modification time of the file changes.
+#[derive(Default)]
+pub struct EtagCache {
+    entries: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl EtagCache {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, (SystemTime, u64, String)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileValidators {
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+impl FileValidators {
+    pub fn for_file(path: &Path, etags: &EtagCache) -> anyhow::Result<FileValidators> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        let length = metadata.len();
+        if let Some((cached_modified, cached_length, etag)) = etags.lock().get(path) {
+            if *cached_modified == modified && *cached_length == length {
+                return Ok(FileValidators { etag: etag.clone(), last_modified: modified });
+            }
+        }
+        let etag = format!("\"{}\"", hash_file(path)?);
+        etags.lock().insert(path.to_path_buf(), (modified, length, etag.clone()));
+        Ok(FileValidators { etag, last_modified: modified })
+    }
+
+    /// Validators of an encoded variant of the file. Each encoding gets its own strong ETag.
+    pub fn for_encoding(&self, encoding: Encoding) -> FileValidators {
+        FileValidators {
+            etag: format!("{}-{}\"", self.etag.trim_end_matches('"'), encoding.name()),
+            last_modified: self.last_modified,
+        }
+    }
+
+    /// Evaluates `If-None-Match`, or `If-Modified-Since` when no entity tags were sent.
+    pub fn is_not_modified(&self, request: &HttpRequest) -> bool {
+        let if_none_match = request.header.get_all("If-None-Match");
+        if !if_none_match.is_empty() {
+            return if_none_match.iter().any(|tag| *tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+        match request.header_value("If-Modified-Since").and_then(|d| httpdate::parse_http_date(d).ok()) {
+            Some(since) => truncate_to_seconds(self.last_modified) <= since,
+            None => false,
+        }
+    }
+
+    pub fn apply(&self, response: &mut HttpResponse, cache_control: &str) {
+        response.set_header("ETag", &self.etag);
+        response.set_header("Last-Modified", &httpdate::fmt_http_date(self.last_modified));
+        response.set_header("Cache-Control", cache_control);
+    }
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize())[..32].to_string())
+}
+
+/// HTTP dates have a resolution of one second.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+/// The `max-age` directive of a `Cache-Control` value, if it is positive.
+pub fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn etags_follow_file_changes() {
+        let path = std::env::temp_dir().join(format!("nemoscene-etag-test-{}.txt", process::id()));
+        fs::write(&path, "first").unwrap();
+        let etags = EtagCache::default();
+        let first = FileValidators::for_file(&path, &etags).unwrap();
+        assert_eq!(FileValidators::for_file(&path, &etags).unwrap().etag, first.etag);
+        assert_eq!(first.for_encoding(Encoding::Brotli).etag, format!("{}-br\"", first.etag.trim_end_matches('"')));
+        fs::write(&path, "second, longer").unwrap();
+        let second = FileValidators::for_file(&path, &etags).unwrap();
+        assert_ne!(second.etag, first.etag);
+        assert_eq!(FileValidators::for_file(&path, &EtagCache::default()).unwrap().etag, second.etag);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn max_age_ignores_other_directives() {
+        assert_eq!(max_age("public, max-age=600"), Some(Duration::from_secs(600)));
+        assert_eq!(max_age("max-age=0"), None);
+        assert_eq!(max_age("no-store"), None);
+    }
+}
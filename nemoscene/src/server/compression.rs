@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Files larger than this are sent uncompressed unless a pre-compressed sibling exists.
+const MAX_COMPRESSED_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+const BROTLI_QUALITY: u32 = 9;
+
+const BROTLI_WINDOW: u32 = 22;
+
+/// Compressed file contents keyed by the ETag of the encoded variant, up to `max_size` bytes.
+pub struct CompressionCache {
+    entries: Mutex<CompressedFiles>,
+    max_size: usize,
+}
+
+#[derive(Default)]
+struct CompressedFiles {
+    entries: HashMap<String, Vec<u8>>,
+    size: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token of the encoding.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The file extension of pre-compressed siblings, e.g. `app.js.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Picks the encoding from an `Accept-Encoding` header. Brotli is preferred when
+    /// both are accepted with the same quality.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+        let accept_encoding = accept_encoding?;
+        let mut qualities: HashMap<String, f32> = HashMap::new();
+        for coding in accept_encoding.split(',') {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>().unwrap_or(0.0)))
+                .next()
+                .unwrap_or(1.0);
+            qualities.insert(name, quality);
+        }
+        let quality = |encoding: Encoding| {
+            qualities.get(encoding.name())
+                .or_else(|| if encoding == Encoding::Gzip { qualities.get("x-gzip") } else { None })
+                .or_else(|| qualities.get("*"))
+                .copied()
+                .unwrap_or(0.0)
+        };
+        [Encoding::Brotli, Encoding::Gzip].into_iter()
+            .filter(|e| quality(*e) > 0.0)
+            .fold(None, |best: Option<Encoding>, e| match best {
+                Some(b) if quality(b) >= quality(e) => Some(b),
+                _ => Some(e),
+            })
+    }
+
+    pub fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+/// Content types worth compressing. Images, fonts and media are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    media_type.starts_with("text/")
+        || matches!(media_type, "application/json" | "application/manifest+json" | "application/javascript" | "application/xml"
+            | "application/wasm" | "image/svg+xml" | "image/x-icon" | "font/ttf" | "font/otf")
+}
+
+/// The encoded contents of a file, either a pre-compressed sibling on disk or
+/// the file compressed in memory.
+pub enum EncodedFile {
+    Sibling(PathBuf),
+    Compressed(Vec<u8>),
+}
+
+impl CompressionCache {
+    pub fn new(max_size: usize) -> CompressionCache {
+        CompressionCache { entries: Mutex::new(CompressedFiles::default()), max_size }
+    }
+
+    /// Encodes a file, preferring a pre-compressed sibling that is at least as new as the file.
+    /// `etag` identifies the encoded variant and is used as the cache key.
+    /// Returns `None` when the file is too large to compress in memory.
+    pub fn encode_file(&self, path: &Path, etag: &str, encoding: Encoding) -> anyhow::Result<Option<EncodedFile>> {
+        let metadata = fs::metadata(path)?;
+        let mut sibling = path.as_os_str().to_os_string();
+        sibling.push(format!(".{}", encoding.extension()));
+        let sibling = PathBuf::from(sibling);
+        if let Ok(sibling_metadata) = fs::metadata(&sibling) {
+            if sibling_metadata.is_file() && sibling_metadata.modified()? >= metadata.modified()? {
+                return Ok(Some(EncodedFile::Sibling(sibling)));
+            }
+        }
+        if metadata.len() > MAX_COMPRESSED_FILE_SIZE {
+            return Ok(None);
+        }
+        if let Some(compressed) = self.lock().entries.get(etag) {
+            return Ok(Some(EncodedFile::Compressed(compressed.clone())));
+        }
+        let compressed = encoding.compress(&fs::read(path)?)?;
+        let mut cache = self.lock();
+        if cache.size + compressed.len() > self.max_size {
+            cache.entries.clear();
+            cache.size = 0;
+        }
+        cache.size += compressed.len();
+        if let Some(replaced) = cache.entries.insert(etag.to_string(), compressed.clone()) {
+            cache.size -= replaced.len();
+        }
+        Ok(Some(EncodedFile::Compressed(compressed)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CompressedFiles> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn brotli_is_preferred_at_equal_quality() {
+        assert_eq!(Encoding::negotiate(Some("gzip, deflate, br")), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate(Some("br;q=0.5, gzip")), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate(Some("identity, br;q=0")), None);
+        assert_eq!(Encoding::negotiate(None), None);
+    }
+
+    #[test]
+    fn compressed_files_stay_within_the_cache_budget() {
+        let path = std::env::temp_dir().join(format!("nemoscene-compression-test-{}.js", process::id()));
+        fs::write(&path, "console.log('hello');\n".repeat(100)).unwrap();
+        let cache = CompressionCache::new(200);
+        let Some(EncodedFile::Compressed(compressed)) = cache.encode_file(&path, "\"a-gzip\"", Encoding::Gzip).unwrap() else {
+            panic!("file was not compressed");
+        };
+        assert!(compressed.len() < 200);
+        assert_eq!(cache.lock().size, compressed.len());
+        cache.encode_file(&path, "\"b-gzip\"", Encoding::Gzip).unwrap();
+        cache.encode_file(&path, "\"c-gzip\"", Encoding::Gzip).unwrap();
+        let files = cache.lock();
+        assert!(files.size <= 200);
+        assert!(files.entries.contains_key("\"c-gzip\""));
+        assert_eq!(files.size, files.entries.values().map(|c| c.len()).sum::<usize>());
+        drop(files);
+        fs::remove_file(&path).unwrap();
+    }
+}
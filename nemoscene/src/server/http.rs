@@ -2,18 +2,31 @@ use std::{
     collections::HashMap,
     error::Error,
     fmt::Display,
-    io::{BufRead, BufReader, Read},
-    net::TcpStream,
+    io::{self, BufRead, BufReader, Read, Write},
+    fs::File,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
-use log::{info, warn};
+use serde::de::DeserializeOwned;
+use log::{error, info, warn};
+use crate::server::tls::Connection;
+
+/// Timeout for requests the server makes to other servers, for the proxy and data providers.
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub enum HttpError {
     Redirect(String),
     NotFound(String),
     BadRequest(String),
+    MethodNotAllowed(String),
+    RequestTimeout,
+    PayloadTooLarge(String),
+    UriTooLong,
+    HeadersTooLarge,
     ServerError(anyhow::Error),
     Unspecified,
 }
@@ -24,6 +37,11 @@ impl Display for HttpError {
             Self::Redirect(target) => write!(f, "Redirection to {}", target),
             Self::BadRequest(msg) => write!(f, "{}", msg),
             Self::NotFound(what) => write!(f, "{}", what),
+            Self::MethodNotAllowed(method) => write!(f, "Method not allowed: {}", method),
+            Self::RequestTimeout => write!(f, "Request timed out"),
+            Self::PayloadTooLarge(msg) => write!(f, "{}", msg),
+            Self::UriTooLong => write!(f, "Request URI too long"),
+            Self::HeadersTooLarge => write!(f, "Request headers too large"),
             Self::ServerError(err) => write!(f, "{}", err.to_string()),
             Self::Unspecified => write!(f, "Unspecified error"),
         }
@@ -38,16 +56,49 @@ impl Into<i32> for &HttpError {
             HttpError::Redirect(_) => 307,
             HttpError::BadRequest(_) => 400,
             HttpError::NotFound(_) => 404,
+            HttpError::MethodNotAllowed(_) => 405,
+            HttpError::RequestTimeout => 408,
+            HttpError::PayloadTooLarge(_) => 413,
+            HttpError::UriTooLong => 414,
+            HttpError::HeadersTooLarge => 431,
             HttpError::ServerError(_) => 500,
             HttpError::Unspecified => 500,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RequestType {
     Get,
+    Head,
     Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl RequestType {
+    pub const ALL: [RequestType; 7] = [Self::Get, Self::Head, Self::Post, Self::Put, Self::Patch, Self::Delete, Self::Options];
+
+    /// Formats methods as the value of an `Allow` header.
+    pub fn allow_header(methods: &[RequestType]) -> String {
+        methods.iter().map(|m| m.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+impl Display for RequestType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
+            Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Patch => write!(f, "PATCH"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Options => write!(f, "OPTIONS"),
+        }
+    }
 }
 
 impl TryFrom<String> for RequestType {
@@ -55,8 +106,13 @@ impl TryFrom<String> for RequestType {
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.to_lowercase().as_str() {
             "get" => Ok(Self::Get),
+            "head" => Ok(Self::Head),
             "post" => Ok(Self::Post),
-            _ => Err(HttpError::BadRequest("Invalid request method".to_string())),
+            "put" => Ok(Self::Put),
+            "patch" => Ok(Self::Patch),
+            "delete" => Ok(Self::Delete),
+            "options" => Ok(Self::Options),
+            _ => Err(HttpError::MethodNotAllowed(value)),
         }
     }
 }
@@ -99,52 +155,216 @@ impl PartialEq for ParameterValue {
 pub struct HttpRequest {
     pub method: RequestType,
     pub route: String,
-    pub header: HashMap<String, String>,
+    pub header: Headers,
     pub get: Option<HashMap<String, ParameterValue>>,
     pub post: Option<HashMap<String, ParameterValue>>,
+    pub files: Option<HashMap<String, FilePart>>,
+    pub body: Vec<u8>,
+    pub route_parameters: HashMap<String, String>,
+    /// Address of the client, set once the request has been read from a connection.
+    pub peer: Option<SocketAddr>,
+    /// Whether the request arrived over TLS.
+    pub secure: bool,
 }
 
-impl HttpRequest {
-    pub fn read_from_stream(stream: &TcpStream) -> anyhow::Result<HttpRequest> {
-        let mut reader = BufReader::new(stream);
-        let mut first_line = String::new();
-        if let Err(err) = reader.read_line(&mut first_line) {
-            bail!(err)
+/// A file uploaded as part of a `multipart/form-data` body.
+#[derive(Debug, Clone)]
+pub struct FilePart {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Request headers. Names are compared case-insensitively and repeated headers keep all their values.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value of a header, including comma-separated values in one line.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(',').map(|v| v.trim()))
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HttpLimits {
+    pub max_body_size: usize,
+    pub max_line_length: usize,
+    pub max_header_count: usize,
+    pub max_header_size: usize,
+    /// Maximum time a single socket read or write may block.
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    /// Maximum time for receiving the complete request, from the request line to the
+    /// last body byte, so clients trickling in bytes cannot hold a worker indefinitely.
+    pub request_timeout: Duration,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        HttpLimits {
+            max_body_size: 16 * 1024 * 1024,
+            max_line_length: 8 * 1024,
+            max_header_count: 100,
+            max_header_size: 64 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LineTooLong;
+
+impl Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line too long")
+    }
+}
+
+impl Error for LineTooLong {}
+
+fn is_line_too_long(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<LineTooLong>().is_some()
+}
+
+/// Reads from a connection until a fixed deadline. Every read blocks for at most the
+/// remaining time (and never longer than `read_timeout`), and fails with `TimedOut`
+/// once the deadline has passed.
+struct DeadlineReader<'a, S: Connection> {
+    stream: &'a mut S,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl<'a, S: Connection> DeadlineReader<'a, S> {
+    fn new(stream: &'a mut S, limits: &HttpLimits) -> DeadlineReader<'a, S> {
+        DeadlineReader {
+            stream,
+            deadline: Instant::now() + limits.request_timeout,
+            read_timeout: limits.read_timeout,
         }
+    }
+}
 
-        let mut header: HashMap<String, String> = HashMap::new();
+impl<S: Connection> Read for DeadlineReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Request deadline exceeded"));
+        }
+        self.stream.socket().set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Reads one line of at most `max_length` bytes and strips the line ending.
+/// Timeouts are reported as `HttpError::RequestTimeout`.
+fn read_line_limited(reader: &mut impl BufRead, max_length: usize) -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    let read = reader.take(max_length as u64 + 1).read_until(b'\n', &mut buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::RequestTimeout.into(),
+        _ => anyhow::Error::from(e),
+    })?;
+    if read == 0 {
+        bail!(HttpError::BadRequest("Connection closed before request was complete".to_string()))
+    }
+    if !buffer.ends_with(b"\n") {
+        if buffer.len() > max_length {
+            bail!(LineTooLong)
+        }
+        bail!(HttpError::BadRequest("Connection closed before request was complete".to_string()))
+    }
+    buffer.pop();
+    if buffer.ends_with(b"\r") {
+        buffer.pop();
+    }
+    String::from_utf8(buffer).map_err(|_| HttpError::BadRequest("Invalid characters in request head".to_string()).into())
+}
+
+impl HttpRequest {
+    pub fn read_from_stream<S: Connection>(stream: &mut S, limits: &HttpLimits) -> anyhow::Result<HttpRequest> {
+        stream.socket().set_write_timeout(Some(limits.write_timeout))?;
+        let peer = stream.socket().peer_addr().ok();
+        let secure = stream.is_secure();
+        let mut reader = BufReader::new(DeadlineReader::new(stream, limits));
+        let mut request = Self::read_from(&mut reader, limits)?;
+        drop(reader);
+        stream.socket().set_read_timeout(Some(limits.read_timeout))?;
+        request.peer = peer;
+        request.secure = secure;
+        Ok(request)
+    }
+
+    /// Parses a request from any buffered reader, enforcing `limits`. Header names are
+    /// matched case-insensitively and repeated headers are kept as separate values.
+    /// The overall deadline is enforced by the reader, see `read_from_stream`.
+    pub fn read_from(reader: &mut impl BufRead, limits: &HttpLimits) -> anyhow::Result<HttpRequest> {
+        let first_line = read_line_limited(reader, limits.max_line_length)
+            .map_err(|e| if is_line_too_long(&e) { HttpError::UriTooLong.into() } else { e })?;
+
+        let mut header = Headers::new();
+        let mut header_size = 0;
         loop {
-            let mut line = String::new();
-            if let Err(err) = reader.read_line(&mut line) {
-                bail!(err)
-            }
-            if line == "\r\n" {
+            let line = read_line_limited(reader, limits.max_line_length)
+                .map_err(|e| if is_line_too_long(&e) { HttpError::HeadersTooLarge.into() } else { e })?;
+            if line.is_empty() {
                 break;
             }
-            if let Some(semicolon) = line.find(':') {
-                header.insert(
-                    line[..semicolon].to_string(),
-                    line[semicolon + 2..line.len() - 2].to_string(),
-                );
+            header_size += line.len();
+            if header.len() >= limits.max_header_count || header_size > limits.max_header_size {
+                bail!(HttpError::HeadersTooLarge)
             }
-        }
-
-        let first_line: Vec<String> = first_line.split(" ").map(|s| s.to_string()).collect();
-
-        let method = if let Some(method) = first_line.get(0) {
-            if let Ok(method) = RequestType::try_from(method.clone()) {
-                method
-            } else {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                bail!(HttpError::BadRequest("Folded header lines are not supported".to_string()))
+            }
+            let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest("Corrupted HTTP headers".to_string()))?;
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control()) {
                 bail!(HttpError::BadRequest("Corrupted HTTP headers".to_string()))
             }
-        } else {
-            bail!(HttpError::BadRequest("Corrupted HTTP headers".to_string()))
-        };
-        let mut route = if let Some(route) = first_line.get(1) {
-            route.clone()
-        } else {
-            bail!(HttpError::BadRequest("Corrupted HTTP headers".to_string()))
+            header.insert(name, value.trim());
+        }
+
+        let mut first_line = first_line.split(' ');
+        let (method, route, version) = match (first_line.next(), first_line.next(), first_line.next(), first_line.next()) {
+            (Some(method), Some(route), Some(version), None) => (method, route, version),
+            _ => bail!(HttpError::BadRequest("Corrupted HTTP request line".to_string())),
         };
+        if !version.starts_with("HTTP/1.") {
+            bail!(HttpError::BadRequest(format!("Unsupported HTTP version: {}", version)))
+        }
+        let method = RequestType::try_from(method.to_string())?;
+        if !route.starts_with('/') && !(method == RequestType::Options && route == "*") {
+            bail!(HttpError::BadRequest("Invalid request target".to_string()))
+        }
+        let mut route = route.to_string();
 
         let get = if let Some(question_mark) = route.find('?') {
             let get = Self::parse_parameters(&route[question_mark + 1..])?;
@@ -154,25 +374,41 @@ impl HttpRequest {
             None
         };
 
-        let post = if let (Some(content_length), Some(content_type)) = (
-            if let Some(content_length) = header.get("Content-Length") {
-                content_length
-                    .parse::<usize>()
-                    .map_or(None, |len| Some(len))
-            } else {
-                None
-            },
-            header.get("Content-Type"),
-        ) {
-            if content_type == "application/x-www-form-urlencoded" && content_length > 0 {
-                let mut buffer: Vec<u8> = vec![0; content_length];
-                reader.read(buffer.as_mut_slice())?;
-                Some(Self::parse_parameters(String::from_utf8(buffer)?.as_str())?)
-            } else {
-                None
+        if header.get("Transfer-Encoding").is_some() {
+            bail!(HttpError::BadRequest("Transfer-Encoding in requests is not supported".to_string()))
+        }
+        let content_lengths = header.get_all("Content-Length");
+        if header.get("Content-Length").is_some() && content_lengths.is_empty() {
+            bail!(HttpError::BadRequest("Invalid Content-Length".to_string()))
+        }
+        if content_lengths.iter().any(|l| *l != content_lengths[0]) {
+            bail!(HttpError::BadRequest("Conflicting Content-Length headers".to_string()))
+        }
+        let content_length = match content_lengths.first() {
+            Some(content_length) => content_length.parse::<usize>()
+                .map_err(|_| HttpError::BadRequest("Invalid Content-Length".to_string()))?,
+            None => 0,
+        };
+        if content_length > limits.max_body_size {
+            bail!(HttpError::PayloadTooLarge(format!("Request body exceeds {} bytes", limits.max_body_size)))
+        }
+        let mut body: Vec<u8> = vec![0; content_length];
+        reader.read_exact(body.as_mut_slice()).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::RequestTimeout.into(),
+            io::ErrorKind::UnexpectedEof => HttpError::BadRequest("Connection closed before request was complete".to_string()).into(),
+            _ => anyhow::Error::from(e),
+        })?;
+
+        let (post, files) = match header.get("Content-Type").map(parse_media_type) {
+            Some((media_type, _)) if media_type == "application/x-www-form-urlencoded" && !body.is_empty() => {
+                (Some(Self::parse_parameters(std::str::from_utf8(&body)?)?), None)
             }
-        } else {
-            None
+            Some((media_type, parameters)) if media_type == "multipart/form-data" => {
+                let boundary = parameters.get("boundary").ok_or(HttpError::BadRequest("Missing multipart boundary".to_string()))?;
+                let (fields, files) = Self::parse_multipart(&body, boundary)?;
+                (Some(fields), Some(files))
+            }
+            _ => (None, None),
         };
 
         Ok(HttpRequest {
@@ -181,30 +417,504 @@ impl HttpRequest {
             header,
             get,
             post,
+            files,
+            body,
+            route_parameters: HashMap::new(),
+            peer: None,
+            secure: false,
         })
     }
 
+    /// Deserializes a JSON request body.
+    pub fn json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        match self.header_value("Content-Type").map(|c| parse_media_type(c).0) {
+            Some(media_type) if media_type == "application/json" => serde_json::from_slice(&self.body)
+                .map_err(|e| HttpError::BadRequest(format!("Invalid JSON body: {}", e)).into()),
+            _ => bail!(HttpError::BadRequest("Expected a JSON request body".to_string())),
+        }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.header_value("Content-Type").map_or(false, |c| parse_media_type(c).0 == "application/json")
+    }
+
+    /// Returns an uploaded file from a multipart body.
+    pub fn file(&self, name: &str) -> Option<&FilePart> {
+        self.files.as_ref()?.get(name)
+    }
+
+    fn parse_multipart(body: &[u8], boundary: &str) -> anyhow::Result<(HashMap<String, ParameterValue>, HashMap<String, FilePart>)> {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut fields: HashMap<String, ParameterValue> = HashMap::new();
+        let mut files: HashMap<String, FilePart> = HashMap::new();
+        // The first part is the preamble, a part starting with "--" is the closing delimiter.
+        for part in split_bytes(body, &delimiter).into_iter().skip(1) {
+            if part.starts_with(b"--") {
+                break;
+            }
+            let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+            let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+            let header_end = find_bytes(part, b"\r\n\r\n").ok_or(HttpError::BadRequest("Invalid multipart body".to_string()))?;
+            let headers = std::str::from_utf8(&part[..header_end]).map_err(|_| HttpError::BadRequest("Invalid multipart headers".to_string()))?;
+            let content = &part[header_end + 4..];
+
+            let mut disposition = None;
+            let mut content_type = None;
+            for line in headers.split("\r\n") {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                        disposition = Some(parse_media_type(value.trim()));
+                    } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                        content_type = Some(value.trim().to_string());
+                    }
+                }
+            }
+            let (_, disposition) = disposition.ok_or(HttpError::BadRequest("Missing Content-Disposition in multipart body".to_string()))?;
+            let name = disposition.get("name").ok_or(HttpError::BadRequest("Missing field name in multipart body".to_string()))?.clone();
+            if let Some(filename) = disposition.get("filename") {
+                files.insert(name, FilePart {
+                    filename: filename.clone(),
+                    content_type: content_type.unwrap_or(String::from("application/octet-stream")),
+                    data: content.to_vec(),
+                });
+            } else {
+                Self::insert_parameter(&mut fields, name, String::from_utf8_lossy(content).to_string())?;
+            }
+        }
+        Ok((fields, files))
+    }
+
+    /// Returns a query string parameter, if present and not an array.
+    pub fn get_parameter(&self, key: &str) -> Option<&String> {
+        self.get.as_ref()?.get(key)?.as_string().ok()
+    }
+
+    /// Returns a form body parameter, if present and not an array.
+    pub fn post_parameter(&self, key: &str) -> Option<&String> {
+        self.post.as_ref()?.get(key)?.as_string().ok()
+    }
+
+    /// Looks up the first value of a header by name, ignoring case.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.header.get(name)
+    }
+
+    /// Returns the value of a cookie sent in the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.header.get_all("Cookie").into_iter()
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Returns a parameter captured from the route pattern, e.g. `:uuid` in `/bundle/:uuid`.
+    pub fn route_parameter(&self, key: &str) -> Option<&String> {
+        self.route_parameters.get(key)
+    }
+
     fn parse_parameters(parameters: &str) -> anyhow::Result<HashMap<String, ParameterValue>> {
         let mut params: HashMap<String, ParameterValue> = HashMap::new();
         for param in parameters.split('&') {
             if let Some(equal_sign) = param.find('=') {
                 let key = url_escape::decode(&param[..equal_sign]).to_string();
                 let value = url_escape::decode(&param[equal_sign + 1..]).to_string();
-                if key.ends_with("[]") {
-                    let key = key[..key.len() - 2].to_string();
-                    let par = params.get_mut(&key);
-                    if let None = par {
-                        params.insert(key, ParameterValue::Array(vec![value]));
-                    } else if let Some(ParameterValue::Array(par)) = par {
-                        par.push(value);
-                    } else {
-                        bail!(HttpError::BadRequest(String::from("Invalid request body")))
+                Self::insert_parameter(&mut params, key, value)?;
+            }
+        }
+        Ok(params)
+    }
+
+    /// Inserts a parameter, collecting keys ending in `[]` into arrays.
+    fn insert_parameter(params: &mut HashMap<String, ParameterValue>, key: String, value: String) -> anyhow::Result<()> {
+        if key.ends_with("[]") {
+            let key = key[..key.len() - 2].to_string();
+            let par = params.get_mut(&key);
+            if let None = par {
+                params.insert(key, ParameterValue::Array(vec![value]));
+            } else if let Some(ParameterValue::Array(par)) = par {
+                par.push(value);
+            } else {
+                bail!(HttpError::BadRequest(String::from("Invalid request body")))
+            }
+        } else {
+            params.insert(key, ParameterValue::String(value));
+        }
+        Ok(())
+    }
+}
+
+/// Splits a header value such as `multipart/form-data; boundary=x` into the lowercase
+/// media type and its parameters. Quoted parameter values are unquoted.
+pub fn parse_media_type(value: &str) -> (String, HashMap<String, String>) {
+    let mut parts = value.split(';');
+    let media_type = parts.next().unwrap_or("").trim().to_lowercase();
+    let parameters = parts.filter_map(|p| p.split_once('=')).map(|(k, v)| {
+        (k.trim().to_lowercase(), v.trim().trim_matches('"').to_string())
+    }).collect();
+    (media_type, parameters)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn split_bytes<'a>(mut data: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    while let Some(position) = find_bytes(data, delimiter) {
+        parts.push(&data[..position]);
+        data = &data[position + delimiter.len()..];
+    }
+    parts.push(data);
+    parts
+}
+
+/// A response body. Bytes and files are sent with a `Content-Length`, streams are
+/// sent with `Transfer-Encoding: chunked` as they are produced.
+pub enum Body {
+    Bytes(Vec<u8>),
+    File(File, u64),
+    Stream(Box<dyn Iterator<Item = anyhow::Result<Vec<u8>>> + Send>),
+}
+
+impl Body {
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, length) => Some(*length),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn into_bytes(self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::File(mut file, length) => {
+                let mut bytes = Vec::with_capacity(length as usize);
+                file.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            Body::Stream(chunks) => {
+                let mut bytes = Vec::new();
+                for chunk in chunks {
+                    bytes.extend(chunk?);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File(_, length) => write!(f, "File({} bytes)", length),
+            Body::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: i32,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl HttpResponse {
+    pub fn new(status: i32) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    pub fn ok(content_type: &str, body: Vec<u8>) -> HttpResponse {
+        Self::new(200).with_header("Content-Type", content_type).with_body(body)
+    }
+
+    /// Responds with the contents of a file, streamed from disk while writing.
+    pub fn file(content_type: &str, path: &Path) -> anyhow::Result<HttpResponse> {
+        let file = File::open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => HttpError::NotFound(format!("File not found: {}", path.display())).into(),
+            _ => anyhow::Error::from(e),
+        })?;
+        let length = file.metadata()?.len();
+        Ok(Self::new(200).with_header("Content-Type", content_type).with_file(file, length))
+    }
+
+    /// Responds with chunks produced by an iterator, using chunked transfer encoding.
+    pub fn stream(content_type: &str, chunks: impl Iterator<Item = anyhow::Result<Vec<u8>>> + Send + 'static) -> HttpResponse {
+        Self::new(200).with_header("Content-Type", content_type).with_stream(chunks)
+    }
+
+    pub fn redirect(target: &str) -> HttpResponse {
+        Self::new(307).with_header("Location", target)
+    }
+
+    /// Unexpected errors are logged here and answered without details, which may reveal internals.
+    pub fn from_error(error: &anyhow::Error) -> HttpResponse {
+        match error.downcast_ref::<HttpError>() {
+            Some(HttpError::Redirect(target)) => Self::redirect(target),
+            Some(HttpError::MethodNotAllowed(_)) => Self::new(405).with_header("Allow", &RequestType::allow_header(&RequestType::ALL)),
+            Some(http_error) => Self::new(http_error.into()).with_header("Content-Type", "text/plain").with_body(http_error.to_string().into_bytes()),
+            None => {
+                error!("Unhandled error: {:#}\n{}", error, error.backtrace());
+                Self::new(500).with_header("Content-Type", "text/plain").with_body(b"Internal Server Error".to_vec())
+            }
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> HttpResponse {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    pub fn with_file(mut self, file: File, length: u64) -> HttpResponse {
+        self.body = Body::File(file, length);
+        self
+    }
+
+    pub fn with_stream(mut self, chunks: impl Iterator<Item = anyhow::Result<Vec<u8>>> + Send + 'static) -> HttpResponse {
+        self.body = Body::Stream(Box::new(chunks));
+        self
+    }
+
+    /// Sets a header, replacing any existing header with the same (case-insensitive) name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    /// Writes the response. For HEAD requests `include_body` is false: the headers,
+    /// including `Content-Length`, are sent as for GET but the body is omitted.
+    /// Every connection serves a single request, so `Connection: close` is always sent,
+    /// except when switching protocols.
+    pub fn write_to(self, stream: &mut impl Write, include_body: bool) -> anyhow::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.status != 101 {
+            head.push_str("Connection: close\r\n");
+        }
+        let has_body = self.status != 101 && self.status != 204 && self.status != 304;
+        match self.body.len() {
+            Some(length) if has_body => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            None if has_body => head.push_str("Transfer-Encoding: chunked\r\n"),
+            _ => {}
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        if include_body && has_body {
+            match self.body {
+                Body::Bytes(bytes) => stream.write_all(&bytes)?,
+                Body::File(file, length) => {
+                    io::copy(&mut file.take(length), stream)?;
+                }
+                Body::Stream(chunks) => {
+                    for chunk in chunks {
+                        let chunk = chunk?;
+                        if chunk.is_empty() {
+                            continue;
+                        }
+                        stream.write_all(format!("{:X}\r\n", chunk.len()).as_bytes())?;
+                        stream.write_all(&chunk)?;
+                        stream.write_all(b"\r\n")?;
+                        stream.flush()?;
                     }
-                } else {
-                    params.insert(key, ParameterValue::String(value));
+                    stream.write_all(b"0\r\n\r\n")?;
                 }
             }
         }
-        Ok(params)
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+pub fn reason_phrase(status: i32) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{TcpListener, TcpStream}, thread};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn limits() -> HttpLimits {
+        HttpLimits {
+            max_body_size: 1024,
+            max_line_length: 256,
+            max_header_count: 10,
+            max_header_size: 1024,
+            ..HttpLimits::default()
+        }
+    }
+
+    /// Parses `data` and returns the response status an error would be reported with.
+    fn parse(data: &[u8]) -> Result<HttpRequest, i32> {
+        HttpRequest::read_from(&mut &data[..], &limits()).map_err(|e| HttpResponse::from_error(&e).status)
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_input_does_not_panic(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            let _ = parse(&data);
+        }
+
+        #[test]
+        fn valid_requests_are_parsed(route in "/[a-z0-9/]{0,40}", headers in proptest::collection::btree_map("x-[a-z0-9-]{0,15}", "[ -~]{0,40}", 0..10)) {
+            let mut data = format!("GET {} HTTP/1.1\r\n", route);
+            for (name, value) in &headers {
+                // Names are matched case-insensitively
+                data.push_str(&format!("{}: {}\r\n", name.to_uppercase(), value));
+            }
+            data.push_str("\r\n");
+            let request = parse(data.as_bytes()).unwrap();
+            prop_assert_eq!(request.route, route);
+            for (name, value) in &headers {
+                prop_assert_eq!(request.header.get(&name.to_uppercase()), Some(value.trim()));
+            }
+        }
+
+        #[test]
+        fn oversized_request_line_is_rejected(length in 257usize..1024) {
+            let data = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(length));
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 414);
+        }
+
+        #[test]
+        fn oversized_header_line_is_rejected(length in 257usize..1024) {
+            let data = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(length));
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 431);
+        }
+
+        #[test]
+        fn too_many_headers_are_rejected(count in 11usize..100) {
+            let mut data = String::from("GET / HTTP/1.1\r\n");
+            for i in 0..count {
+                data.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+            }
+            data.push_str("\r\n");
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 431);
+        }
+
+        #[test]
+        fn invalid_content_length_is_rejected(length in "[^0-9\r\n]{1,20}|-[0-9]{1,5}|[0-9]{30}") {
+            let data = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 400);
+        }
+
+        #[test]
+        fn conflicting_content_lengths_are_rejected(first in 0usize..100, second in 0usize..100) {
+            prop_assume!(first != second);
+            let data = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\nContent-Length: {}\r\n\r\n", first, second);
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 400);
+        }
+
+        #[test]
+        fn oversized_body_is_rejected(length in 1025usize..1_000_000) {
+            let data = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+            prop_assert_eq!(parse(data.as_bytes()).unwrap_err(), 413);
+        }
+
+        #[test]
+        fn truncated_body_is_rejected(body in proptest::collection::vec(any::<u8>(), 0..512), missing in 1usize..512) {
+            let mut data = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len() + missing).into_bytes();
+            data.extend_from_slice(&body);
+            prop_assert_eq!(parse(&data).unwrap_err(), 400);
+        }
+
+        #[test]
+        fn truncated_head_is_rejected(cut in 0usize..40) {
+            let data = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            prop_assert_eq!(parse(&data[..cut.min(data.len() - 1)]).unwrap_err(), 400);
+        }
+
+        #[test]
+        fn non_utf8_head_is_rejected(prefix in "[a-z]{0,10}", invalid in prop_oneof![Just(vec![0xff]), Just(vec![0xc3, 0x28]), Just(vec![0xe2, 0x82])]) {
+            let mut data = format!("GET / HTTP/1.1\r\nX-Value: {}", prefix).into_bytes();
+            data.extend_from_slice(&invalid);
+            data.extend_from_slice(b"\r\n\r\n");
+            prop_assert_eq!(parse(&data).unwrap_err(), 400);
+        }
+    }
+
+    #[test]
+    fn request_deadline_covers_slow_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+            // Every single read completes well within the read timeout.
+            for _ in 0..40 {
+                if stream.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let limits = HttpLimits {
+            read_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_millis(500),
+            ..limits()
+        };
+        let started = Instant::now();
+        let error = HttpRequest::read_from_stream(&mut stream, &limits).unwrap_err();
+        assert_eq!(HttpResponse::from_error(&error).status, 408);
+        assert!(started.elapsed() < Duration::from_millis(1500));
+        drop(stream);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn unhandled_errors_hide_their_details() {
+        let response = HttpResponse::from_error(&anyhow::anyhow!("Cannot open /var/lib/nemoscene/secrets"));
+        assert_eq!(response.status, 500);
+        match response.body {
+            Body::Bytes(body) => assert_eq!(body, b"Internal Server Error"),
+            _ => panic!("Unexpected body"),
+        }
     }
 }
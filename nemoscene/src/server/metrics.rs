@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::configuration;
+use crate::events::{Event, EventBus};
+use crate::server::threadpool::PoolStatistics;
+use crate::system_state::SystemState;
+
+/// Upper bounds of the request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Views attached to the dashboard, by bundle. Views stay on the dashboard across a reload,
+/// but only count again once their bundle is loaded again.
+#[derive(Default)]
+struct AttachedViews {
+    current: HashSet<String>,
+    before_reload: HashSet<String>,
+}
+
+impl AttachedViews {
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::ViewAttached { uuid } => { self.current.insert(uuid.clone()); }
+            Event::BundleUnloaded { uuid } => {
+                self.current.remove(uuid);
+                self.before_reload.remove(uuid);
+            }
+            Event::Reloaded => self.before_reload = mem::take(&mut self.current),
+            Event::BundleLoaded { uuid } if self.before_reload.remove(uuid) => { self.current.insert(uuid.clone()); }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Latency {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters and gauges exported in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    started: Instant,
+    ready: AtomicBool,
+    requests: Mutex<BTreeMap<(String, String, i32), u64>>,
+    latencies: Mutex<BTreeMap<(String, String), Latency>>,
+    pool: Mutex<Option<Arc<PoolStatistics>>>,
+    attached_views: Mutex<AttachedViews>,
+    view_crashes: AtomicU64,
+    view_load_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            ready: AtomicBool::new(false),
+            requests: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(BTreeMap::new()),
+            pool: Mutex::new(None),
+            attached_views: Mutex::new(AttachedViews::default()),
+            view_crashes: AtomicU64::new(0),
+            view_load_failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts dashboard events.
+    pub fn watch_events(self: &Arc<Self>, events: &EventBus) {
+        let metrics = self.clone();
+        events.listen(move |event| {
+            metrics.attached_views.lock().unwrap_or_else(PoisonError::into_inner).update(&event);
+            match event {
+                Event::ViewCrashed { .. } => { metrics.view_crashes.fetch_add(1, Ordering::Relaxed); }
+                Event::ViewLoadFailed { .. } => { metrics.view_load_failures.fetch_add(1, Ordering::Relaxed); }
+                Event::ShuttingDown => metrics.set_ready(false),
+                _ => {}
+            }
+        });
+    }
+
+    pub fn watch_pool(&self, statistics: Arc<PoolStatistics>) {
+        *self.pool.lock().unwrap_or_else(PoisonError::into_inner) = Some(statistics);
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Records a handled request. `route` is the matched route pattern, so the number
+    /// of label values stays bounded.
+    pub fn record_request(&self, route: &str, method: &str, status: i32, duration: Duration) {
+        *self.requests.lock().unwrap_or_else(PoisonError::into_inner)
+            .entry((route.to_string(), method.to_string(), status))
+            .or_insert(0) += 1;
+        let seconds = duration.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap_or_else(PoisonError::into_inner);
+        let latency = latencies.entry((route.to_string(), method.to_string())).or_default();
+        for (bucket, bound) in latency.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        latency.sum += seconds;
+        latency.count += 1;
+    }
+
+    pub fn render(&self, system_state: &SystemState) -> String {
+        let mut out = String::new();
+        header(&mut out, "nemoscene_http_requests_total", "counter", "HTTP requests by route pattern, method and status.");
+        for ((route, method, status), count) in self.requests.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let _ = writeln!(out, "nemoscene_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}", escape(route), method, status, count);
+        }
+        header(&mut out, "nemoscene_http_request_duration_seconds", "histogram", "HTTP request latency by route pattern and method.");
+        for ((route, method), latency) in self.latencies.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape(route), method);
+            for (count, bound) in latency.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(out, "nemoscene_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "nemoscene_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, latency.count);
+            let _ = writeln!(out, "nemoscene_http_request_duration_seconds_sum{{{}}} {}", labels, latency.sum);
+            let _ = writeln!(out, "nemoscene_http_request_duration_seconds_count{{{}}} {}", labels, latency.count);
+        }
+        if let Some(pool) = self.pool.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+            gauge(&mut out, "nemoscene_threadpool_queue_depth", "Requests waiting for a worker thread.", pool.queued() as f64);
+            gauge(&mut out, "nemoscene_threadpool_active_workers", "Worker threads handling a request.", pool.active() as f64);
+        }
+        gauge(&mut out, "nemoscene_bundles_loaded", "Bundles loaded.", system_state.app_manager().bundles().count() as f64);
+        gauge(&mut out, "nemoscene_views_attached", "Views attached to the dashboard.", self.attached_views.lock().unwrap_or_else(PoisonError::into_inner).current.len() as f64);
+        gauge(&mut out, "nemoscene_websocket_subscribers", "Open WebSocket sessions and internal subscribers.", system_state.pubsub.subscriber_count() as f64);
+        counter(&mut out, "nemoscene_view_crashes_total", "WebKit web process crashes.", self.view_crashes.load(Ordering::Relaxed));
+        counter(&mut out, "nemoscene_view_load_failures_total", "Failed page loads in views.", self.view_load_failures.load(Ordering::Relaxed));
+        counter(&mut out, "nemoscene_configuration_commit_failures_total", "Configuration bases that could not be written to disk.", configuration::commit_failures());
+        gauge(&mut out, "nemoscene_ready", "Whether the instance is ready to serve.", if self.is_ready() { 1.0 } else { 0.0 });
+        gauge(&mut out, "nemoscene_uptime_seconds", "Seconds since the instance started.", self.started.elapsed().as_secs_f64());
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attached_views_follow_bundles() {
+        let mut views = AttachedViews::default();
+        for uuid in ["clock", "weather", "calendar"] {
+            views.update(&Event::ViewAttached { uuid: uuid.to_string() });
+        }
+        views.update(&Event::BundleUnloaded { uuid: String::from("calendar") });
+        assert_eq!(views.current.len(), 2);
+        views.update(&Event::Reloaded);
+        assert!(views.current.is_empty());
+        views.update(&Event::BundleLoaded { uuid: String::from("clock") });
+        views.update(&Event::BundleLoaded { uuid: String::from("calendar") });
+        assert_eq!(views.current, HashSet::from([String::from("clock")]));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use once_cell::sync::Lazy;
+
+/// Number of leading bytes inspected when sniffing the type of a file.
+const SNIFF_LENGTH: u64 = 512;
+
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Media types by lower-case file extension.
+static MIME_TYPES: Lazy<BTreeMap<&str, &str>> = Lazy::new(|| BTreeMap::from([
+    // Text and documents
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    // Fonts
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("aac", "audio/aac"),
+    ("m4a", "audio/mp4"),
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+]));
+
+/// Looks up the media type of a file extension, ignoring case.
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    MIME_TYPES.get(extension.to_ascii_lowercase().as_str()).copied()
+}
+
+/// Guesses the media type from the leading bytes of a file.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    let signatures: [(&[u8], &str); 17] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x00asm", "application/wasm"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OTTO", "font/otf"),
+        (b"\x00\x01\x00\x00", "font/ttf"),
+        (b"\x00\x00\x01\x00", "image/x-icon"),
+    ];
+    if let Some((_, media_type)) = signatures.iter().find(|(signature, _)| bytes.starts_with(signature)) {
+        return Some(media_type);
+    }
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" {
+        match &bytes[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"M4A " => Some("audio/mp4"),
+            b"qt  " => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        };
+    }
+    if bytes.len() >= 2 && bytes[0] == 0xff && bytes[1] & 0xe0 == 0xe0 {
+        return Some("audio/mpeg");
+    }
+    sniff_text(bytes)
+}
+
+/// Recognizes markup by its first tag; any other UTF-8 without control characters is plain text.
+fn sniff_text(bytes: &[u8]) -> Option<&'static str> {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // The sample may end inside a multi-byte character
+        Err(error) if error.error_len().is_none() => std::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap(),
+        Err(_) => return None,
+    };
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let lower = text.chars().take(256).collect::<String>().to_ascii_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        Some("text/html")
+    } else if lower.starts_with("<svg") || (lower.starts_with("<?xml") && lower.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if lower.starts_with("<?xml") {
+        Some("application/xml")
+    } else if text.chars().all(|c| !c.is_control() || c.is_whitespace()) {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+fn sniff_file(path: &Path) -> Option<&'static str> {
+    let mut bytes = Vec::with_capacity(SNIFF_LENGTH as usize);
+    File::open(path).ok()?.take(SNIFF_LENGTH).read_to_end(&mut bytes).ok()?;
+    sniff(&bytes)
+}
+
+/// Text types are served as UTF-8.
+pub fn with_charset(media_type: &str) -> String {
+    let is_text = media_type.starts_with("text/")
+        || matches!(media_type, "application/javascript" | "application/xml" | "image/svg+xml");
+    if is_text && !media_type.contains(';') {
+        format!("{}; charset=utf-8", media_type)
+    } else {
+        media_type.to_string()
+    }
+}
+
+/// Resolves the `Content-Type` of a file: the override declared for its extension, then
+/// the extension database, then the file contents, then `application/octet-stream`.
+pub fn content_type(path: &Path, overrides: &BTreeMap<String, String>) -> String {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    if let Some(media_type) = extension.as_ref().and_then(|e| overrides.get(e)) {
+        return with_charset(media_type);
+    }
+    let media_type = extension.as_deref().and_then(from_extension)
+        .or_else(|| sniff_file(path))
+        .unwrap_or(DEFAULT_TYPE);
+    with_charset(media_type)
+}
@@ -1,160 +1,325 @@
+pub mod admin;
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod compression;
 pub mod http;
+pub mod metrics;
+pub mod mime;
+pub mod proxy;
+pub mod pubsub;
+pub mod range;
+pub mod router;
+pub mod secrets;
 pub mod threadpool;
+pub mod tls;
+pub mod websocket;
 
 use html_to_string_macro::*;
 use log::{error, info, warn};
-use once_cell::sync::Lazy;
 use std::{
     collections::HashMap,
     error::Error,
     fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{self, prelude::*, BufReader},
+    net::TcpListener,
     sync::*,
+    sync::atomic::{AtomicBool, Ordering},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
-use std::collections::BTreeMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
 use anyhow::anyhow;
-use crate::get_system_state;
+use serde_json::json;
+use crate::dashboard::{DashboardMessage, SCREENSHOT_TIMEOUT};
+use crate::system_state::SystemState;
 
-use self::http::{HttpError, ParameterValue};
+use self::http::{HttpError, HttpLimits, HttpRequest, HttpResponse, ParameterValue, RequestType};
+use self::auth::{AuthMiddleware, HostMiddleware};
+use self::cache::FileValidators;
+use self::compression::{EncodedFile, Encoding};
+use self::range::RangeRequest;
+use self::tls::Connection;
+use self::router::{CorsMiddleware, LoggingMiddleware, Router};
 
-static CONTENT_TYPES: Lazy<BTreeMap<&str, &str>> = Lazy::new(|| BTreeMap::from([
-    ("html", "text/html"),
-    ("txt", "text/plain"),
-    ("ttf", "font/ttf"),
-    ("ico", "image/x-icon"),
-    ("js", "text/javascript"),
-    ("json", "application/json"),
-]));
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
-pub fn run_server() -> ! {
-    let listener = TcpListener::bind("0.0.0.0:1337").unwrap();
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Public monitoring endpoints, the only routes other origins may call.
+const MONITORING_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+/// A bound socket, plain or TLS, created by `bind_listeners`.
+pub struct Listener {
+    listener: TcpListener,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Accepts clients on `listeners` until `stop_server` is called. Requests already
+/// accepted are drained by the thread pool before this function returns.
+pub fn run_server(system_state: SystemState, listeners: Vec<Listener>) {
     let pool = threadpool::ThreadPool::new(4);
+    system_state.metrics.watch_pool(pool.statistics());
+    let router = Arc::new(build_router());
+    let limits = load_limits(&system_state);
+    system_state.running.store(true, Ordering::SeqCst);
     info!("Accepting clients");
-    loop {
-        let (stream, address) = match listener.accept() {
-            Err(err) => {
+    while is_running(&system_state) {
+        let mut accepted = false;
+        for listener in &listeners {
+            let stream = match listener.listener.accept() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => {
+                    error!("Client connection error: {}", err);
+                    continue;
+                }
+                Ok((stream, _)) => stream,
+            };
+            accepted = true;
+            if let Err(err) = stream.set_nonblocking(false) {
                 error!("Client connection error: {}", err);
                 continue;
             }
-            Ok((stream, address)) => (stream, address),
-        };
-        pool.execute(move || {
-            if let Err(err) = handle_connection(stream) {
-                error!("Server error: {:?}", err);
+            let system_state = system_state.clone();
+            let router = router.clone();
+            match &listener.tls {
+                Some(tls) => {
+                    let connection = match rustls::ServerConnection::new(tls.clone()) {
+                        Ok(connection) => connection,
+                        Err(err) => {
+                            error!("TLS error: {}", err);
+                            continue;
+                        }
+                    };
+                    let stream = rustls::StreamOwned::new(connection, stream);
+                    pool.execute(move || {
+                        if let Err(err) = handle_connection(&system_state, &router, &limits, stream) {
+                            error!("Server error: {:?}", err);
+                        }
+                    });
+                }
+                None => pool.execute(move || {
+                    if let Err(err) = handle_connection(&system_state, &router, &limits, stream) {
+                        error!("Server error: {:?}", err);
+                    }
+                }),
             }
-        });
+        }
+        if !accepted {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+    }
+    info!("Server stopped accepting clients, draining requests");
+}
+
+/// Binds the plain HTTP listener, and the HTTPS listener if `tls` is enabled in the
+/// `nemoscene` configuration base. With TLS enabled, plain HTTP is only served on
+/// loopback for the local web views. Binding happens before the server thread starts,
+/// so a port that is already in use fails startup instead of the server thread.
+pub fn bind_listeners(system_state: &SystemState) -> anyhow::Result<Vec<Listener>> {
+    let arguments = &system_state.arguments;
+    let (tls, tls_port, certificate, key) = {
+        let configuration = system_state.configuration();
+        let base = configuration.get_base(&arguments.data_path("configuration/nemoscene"));
+        (
+            base.and_then(|b| b.get_bool("tls")).unwrap_or(false),
+            base.and_then(|b| b.get_i64("tls_port")).unwrap_or(1338) as u16,
+            base.and_then(|b| b.get_str("tls_certificate")).unwrap_or(String::from("tls/certificate.pem")),
+            base.and_then(|b| b.get_str("tls_key")).unwrap_or(String::from("tls/key.pem")),
+        )
+    };
+    let mut listeners = Vec::new();
+    let plain_address = if tls { format!("127.0.0.1:{}", arguments.port) } else { arguments.bind_address() };
+    listeners.push(Listener { listener: bind(&plain_address)?, tls: None });
+    info!("Listening on http://{}", plain_address);
+    if tls {
+        let config = tls::load_server_config(&PathBuf::from(arguments.data_path(&certificate)), &PathBuf::from(arguments.data_path(&key)))?;
+        let tls_address = format!("{}:{}", arguments.bind, tls_port);
+        listeners.push(Listener { listener: bind(&tls_address)?, tls: Some(config) });
+        info!("Listening on https://{}", tls_address);
+    }
+    Ok(listeners)
+}
+
+fn bind(address: &str) -> anyhow::Result<TcpListener> {
+    let listener = TcpListener::bind(address).map_err(|e| anyhow!("Cannot listen on {}: {}", address, e))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub fn stop_server(system_state: &SystemState) {
+    system_state.running.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running(system_state: &SystemState) -> bool {
+    system_state.running.load(Ordering::SeqCst)
+}
+
+fn build_router() -> Router {
+    let mut router = Router::new();
+    router
+        .wrap(LoggingMiddleware)
+        .wrap(HostMiddleware)
+        .wrap(CorsMiddleware { allowed_origin: String::from("*"), routes: &MONITORING_ROUTES })
+        .wrap(AuthMiddleware)
+        .get("/bundle/:uuid/*route", serve_file)
+        .get("/config", serve_config)
+        .get("/ws", websocket::upgrade)
+        .get("/admin/screenshot", serve_screenshot)
+        .get("/healthz", serve_health)
+        .get("/readyz", serve_readiness)
+        .get("/metrics", |state, _| Ok(HttpResponse::ok("text/plain; version=0.0.4; charset=utf-8", state.metrics.render(state).into_bytes())))
+        .get("/favicon.ico", |_, _| Ok(HttpResponse::ok("image/x-icon", Vec::new())));
+    api::register_routes(&mut router);
+    admin::register_routes(&mut router);
+    auth::register_routes(&mut router);
+    proxy::register_routes(&mut router);
+    router
+}
+
+/// Reads request limits from the `nemoscene` configuration base, falling back to defaults.
+fn load_limits(system_state: &SystemState) -> HttpLimits {
+    let mut limits = HttpLimits::default();
+    let configuration = system_state.configuration();
+    if let Some(base) = configuration.get_base(&system_state.arguments.data_path("configuration/nemoscene")) {
+        if let Some(max_body_size) = base.get_i64("max_body_size") {
+            limits.max_body_size = max_body_size as usize;
+        }
+        if let Some(max_header_count) = base.get_i64("max_header_count") {
+            limits.max_header_count = max_header_count as usize;
+        }
+        if let Some(read_timeout) = base.get_i64("read_timeout") {
+            limits.read_timeout = Duration::from_secs(read_timeout as u64);
+        }
+        if let Some(request_timeout) = base.get_i64("request_timeout") {
+            limits.request_timeout = Duration::from_secs(request_timeout as u64);
+        }
     }
+    limits
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
+fn handle_connection<S: Connection>(
+    system_state: &SystemState,
+    router: &Router,
+    limits: &HttpLimits,
+    mut stream: S,
 ) -> anyhow::Result<()> {
-    let request = http::HttpRequest::read_from_stream(&stream)?;
-    //info!("Read request: {:#?}", request);
-    let mut parts = request.route.split('/');
-    parts.next();
-    let result = if let Ok(request_type) = parts.next().ok_or(anyhow!("Invalid request")) {
-        if request_type == "bundle" {
-            if let Some(uuid) = parts.next() {
-                let route = parts.map(|s| s.to_string()).collect::<Vec<String>>().join("/");
-                match serve_file(
-                    uuid,
-                    route.as_str(),
-                    request.get,
-                    request.post,
-                ) {
-                    Err(error) => Err(error),
-                    Ok((content_type, content)) => respond(&mut stream, 200, content_type, content)
-                }
-            } else {
-                Err(anyhow!("Invalid request"))
-            }
-        } else if request_type == "config" {
-            match serve_config(request.get) {
-                Err(error) => Err(error),
-                Ok(content) => respond(&mut stream, 200, String::from(*CONTENT_TYPES.get("json").unwrap()), content),
-            }
-        } else if request_type == "favicon.ico" {
-            respond(&mut stream, 200, String::from(*CONTENT_TYPES.get("ico").unwrap()), Vec::new())
-        } else {
-            Err(anyhow!("Invalid request type: {}", request_type))
+    let (request_method, response) = match http::HttpRequest::read_from_stream(&mut stream, limits) {
+        Ok(request) => {
+            let started = Instant::now();
+            let response = router.handle(system_state, &request);
+            system_state.metrics.record_request(router.route_label(&request), &request.method.to_string(), response.status, started.elapsed());
+            (Some(request.method), response)
         }
-    } else {
-        Err(anyhow!("Invalid request"))
+        Err(error) => (None, HttpResponse::from_error(&error)),
     };
-    if let Err(error) = result {
-        error_response(&mut stream, error.to_string(), Some(error.backtrace().to_string()))
-    } else {
-        Ok(())
+    let upgraded = response.status == 101;
+    response.write_to(&mut stream, request_method != Some(RequestType::Head))?;
+    if upgraded {
+        // WebSocket sessions are long-lived, so they get their own thread instead of a pool worker.
+        let system_state = system_state.clone();
+        thread::spawn(move || {
+            if let Err(err) = websocket::run_session(&system_state, stream) {
+                error!("WebSocket error: {:?}", err);
+            }
+        });
     }
+    Ok(())
 }
 
-fn serve_config(get: Option<HashMap<String, ParameterValue>>) -> anyhow::Result<Vec<u8>> {
-    if let Some(get) = get {
-        if let (Some(uuid), Some(base), Some(key)) = (get.get("uuid").map(|v| v.as_string().cloned().unwrap_or(String::new())), get.get("base").map(|v| v.as_string().cloned().unwrap_or(String::new())), get.get("key").map(|v| v.as_string().cloned().unwrap_or(String::new()))) {
-            let path = format!("data/bundles/{}/config/{}", uuid, base);
-            if let Some(base) = get_system_state!().configuration.get_base(path.as_str()) {
-                base.get_json(key.as_str()).ok_or(anyhow!("Invalid configuration key: {}", key))
-            } else {
-                Err(anyhow!("Invalid configuration base: {}", base))
-            }
+fn serve_config(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    if let (Some(uuid), Some(base), Some(key)) = (request.get_parameter("uuid"), request.get_parameter("base"), request.get_parameter("key")) {
+        let path = system_state.arguments.data_path(&format!("bundles/{}/config/{}", uuid, base));
+        if let Some(base) = system_state.configuration().get_base(path.as_str()) {
+            let content = base.get_json(key.as_str()).ok_or(HttpError::NotFound(format!("Invalid configuration key: {}", key)))?;
+            Ok(HttpResponse::ok("application/json", content))
         } else {
-            Err(anyhow!("Invalid request"))
+            Err(HttpError::NotFound(format!("Invalid configuration base: {}", base)).into())
         }
     } else {
-        Err(anyhow!("Invalid request"))
+        Err(HttpError::BadRequest(String::from("Invalid request")).into())
     }
 }
 
-fn serve_file(
-    uuid: &str,
-    route: &str,
-    get: Option<HashMap<String, ParameterValue>>,
-    post: Option<HashMap<String, ParameterValue>>,
-) -> anyhow::Result<(String, Vec<u8>)> {
-    let base_path = {
-        get_system_state!().app_manager.get_bundle(uuid).ok_or(anyhow!("Bundle not found: {}", uuid))?.base_path.clone()
+fn serve_screenshot(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let receiver = system_state.dashboard.screenshot(request.get_parameter("view").map(|v| v.as_str()))?;
+    Ok(HttpResponse::ok("image/png", receiver.recv_timeout(SCREENSHOT_TIMEOUT)??))
+}
+
+/// Healthy as long as requests are served and the dashboard's UI thread responds.
+fn serve_health(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let (sender, receiver) = mpsc::channel();
+    system_state.dashboard.send_message(DashboardMessage::ListViews(sender))?;
+    let dashboard = receiver.recv_timeout(HEALTH_CHECK_TIMEOUT).is_ok();
+    let status = if dashboard { 200 } else { 503 };
+    Ok(HttpResponse::new(status).with_header("Content-Type", "application/json").with_body(
+        json!({ "status": if dashboard { "ok" } else { "unhealthy" }, "checks": { "dashboard": dashboard } }).to_string().into_bytes()
+    ))
+}
+
+/// Ready once initialization has finished, and not while reloading or shutting down.
+fn serve_readiness(system_state: &SystemState, _request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let ready = system_state.metrics.is_ready() && is_running(system_state);
+    Ok(HttpResponse::new(if ready { 200 } else { 503 }).with_header("Content-Type", "application/json").with_body(
+        json!({ "status": if ready { "ready" } else { "not ready" } }).to_string().into_bytes()
+    ))
+}
+
+fn serve_file(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let uuid = request.route_parameter("uuid").unwrap();
+    let (base_path, cache_control, content_types) = {
+        let app_manager = system_state.app_manager();
+        let bundle = app_manager.get_bundle(uuid).ok_or(HttpError::NotFound(format!("Bundle not found: {}", uuid)))?;
+        let folder = request.route_parameter("route").unwrap().split('/').next().unwrap_or("app").to_string();
+        (bundle.base_path.clone(), bundle.cache_control(&folder).to_string(), bundle.content_types.clone())
     };
-    let mut route = PathBuf::from(route);
+    let mut route = PathBuf::from(request.route_parameter("route").unwrap());
     if route.components().count() == 0 {
         route = PathBuf::from("app/index.html");
     }
+    if route.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(HttpError::BadRequest(format!("Invalid file path: {}", route.display())).into());
+    }
     let path = PathBuf::from(base_path).join(route);
-    let extension = String::from(path.extension().map_or("txt", |e| e.to_str().unwrap()));
-    let path = path.to_str().unwrap().to_string();
-    let content = fs::read(path)?;
-    let content_type = String::from(*CONTENT_TYPES.get(extension.as_str()).unwrap_or(&"txt"));
-    Ok((content_type, content))
-}
-
-fn error_response(stream: &mut TcpStream, message: String, backtrace: Option<String>) -> anyhow::Result<()> {
-    respond(stream, 500, String::from(*CONTENT_TYPES.get("txt").unwrap()), format!("{}\n{}", message, backtrace.or(Some(String::new())).unwrap()).into_bytes())
-}
-
-fn respond(stream: &mut TcpStream, status_code: i32, content_type: String, contents: Vec<u8>) -> anyhow::Result<()> {
-    let response = if status_code == 307 {
-        format!(
-            "{}\r\nLocation: {}\r\n\r\n",
-            "HTTP/1.1 307 OK",
-            unsafe { std::str::from_utf8_unchecked(contents.as_slice()) },
-        )
+    let mut validators = FileValidators::for_file(&path, &system_state.etags).map_err(|_| HttpError::NotFound(format!("File not found: {}", path.display())))?;
+    let content_type = mime::content_type(&path, &content_types);
+    let content_type = content_type.as_str();
+    let compressible = compression::is_compressible(content_type);
+    // Ranges always address the unencoded file, so range requests are answered uncompressed.
+    let encoding = if compressible && request.header_value("Range").is_none() { Encoding::negotiate(request.header_value("Accept-Encoding")) } else { None };
+    let encoded = match encoding {
+        Some(encoding) => {
+            let encoded_validators = validators.for_encoding(encoding);
+            system_state.compression.encode_file(&path, &encoded_validators.etag, encoding)?.map(|encoded| {
+                validators = encoded_validators;
+                (encoding, encoded)
+            })
+        }
+        None => None,
+    };
+    let mut response = if validators.is_not_modified(request) {
+        HttpResponse::new(304)
     } else {
-        format!(
-            "{}\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n{}",
-            format!("HTTP/1.1 {} OK", status_code),
-            contents.len(),
-            content_type,
-            unsafe { std::str::from_utf8_unchecked(contents.as_slice()) },
-        )
+        match encoded {
+            Some((encoding, EncodedFile::Sibling(sibling))) => HttpResponse::file(content_type, &sibling)?.with_header("Content-Encoding", encoding.name()),
+            Some((encoding, EncodedFile::Compressed(bytes))) => HttpResponse::ok(content_type, bytes).with_header("Content-Encoding", encoding.name()),
+            None => {
+                let length = fs::metadata(&path)?.len();
+                let response = match RangeRequest::from_request(request, &validators, length) {
+                    RangeRequest::Full => HttpResponse::file(content_type, &path)?,
+                    RangeRequest::Partial(ranges) => range::partial_response(content_type, &path, &ranges)?,
+                    RangeRequest::Unsatisfiable => range::unsatisfiable_response(length),
+                };
+                response.with_header("Accept-Ranges", "bytes")
+            }
+        }
     };
-    stream.write_all(response.as_bytes())?;
-    stream.flush()?;
-    Ok(())
+    validators.apply(&mut response, &cache_control);
+    if compressible {
+        response.set_header("Vary", "Accept-Encoding");
+    }
+    Ok(response)
 }
 
 #[macro_export]
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail};
+use log::{info, warn};
+use toml::Table;
+use url::Url;
+use crate::server::cache::max_age;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse, RequestType, UPSTREAM_TIMEOUT};
+use crate::server::router::Router;
+use crate::server::secrets::Secrets;
+use crate::system_state::SystemState;
+
+const MAX_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+
+const DEFAULT_RATE_LIMIT: u32 = 60;
+
+/// Request headers passed on to the upstream server. Cookies and credentials of the
+/// client are meant for Nemoscene and are never forwarded.
+const FORWARDED_REQUEST_HEADERS: [&str; 3] = ["Accept", "Accept-Language", "Content-Type"];
+
+const FORWARDED_RESPONSE_HEADERS: [&str; 6] = ["Content-Type", "Cache-Control", "ETag", "Last-Modified", "Location", "Retry-After"];
+
+/// The upstream connections, cached responses and rate limits shared by all proxy requests.
+pub struct Proxy {
+    /// Redirects are returned to the widget instead of being followed, so they cannot lead past the allow-list.
+    agent: ureq::Agent,
+    cache: Mutex<ResponseCache>,
+    /// Memory budget of the cached response bodies.
+    max_cache_size: usize,
+    /// Remaining requests and the time of the last refill per bundle.
+    rate_limits: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+struct CachedResponse {
+    status: i32,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<String, CachedResponse>,
+    size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum InjectionTarget {
+    Header(String),
+    Query(String),
+}
+
+/// A secret added to requests for an https origin, as a header or query parameter.
+/// `format` is the value with `{}` standing for the secret, e.g. `Bearer {}`.
+#[derive(Debug, Clone)]
+pub struct SecretInjection {
+    /// Host name pattern, as in `allowed_hosts`.
+    pub host: String,
+    pub port: u16,
+    pub secret: String,
+    pub target: InjectionTarget,
+    pub format: String,
+}
+
+/// Proxy settings of a bundle, from the `[proxy]` table of its manifest:
+///
+/// ```toml
+/// [proxy]
+/// allowed_hosts = ["www.googleapis.com", "*.example.com"]
+/// rate_limit = 60
+/// cache_ttl = 300
+///
+/// [[proxy.secrets]]
+/// origin = "https://www.googleapis.com"
+/// secret = "api_key"
+/// query = "key"
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProxyRules {
+    pub allowed_hosts: Vec<String>,
+    /// Requests per minute.
+    pub rate_limit: u32,
+    /// Overrides the `max-age` of upstream responses.
+    pub cache_ttl: Option<Duration>,
+    pub secrets: Vec<SecretInjection>,
+}
+
+impl ProxyRules {
+    pub fn from_table(table: &Table) -> anyhow::Result<ProxyRules> {
+        let allowed_hosts = match table.get("allowed_hosts") {
+            Some(hosts) => hosts.as_array().ok_or(anyhow!("allowed_hosts must be an array of host names"))?.iter()
+                .map(|h| h.as_str().map(|h| h.to_ascii_lowercase()).ok_or(anyhow!("allowed_hosts must be an array of host names")))
+                .collect::<anyhow::Result<Vec<String>>>()?,
+            None => Vec::new(),
+        };
+        let secrets = match table.get("secrets") {
+            Some(secrets) => secrets.as_array().ok_or(anyhow!("secrets must be an array of tables"))?.iter()
+                .map(|s| s.as_table().ok_or(anyhow!("secrets must be an array of tables")).and_then(SecretInjection::from_table))
+                .collect::<anyhow::Result<Vec<SecretInjection>>>()?,
+            None => Vec::new(),
+        };
+        Ok(ProxyRules {
+            allowed_hosts,
+            rate_limit: table.get("rate_limit").and_then(|r| r.as_integer()).map_or(DEFAULT_RATE_LIMIT, |r| u32::try_from(r.max(1)).unwrap_or(u32::MAX)),
+            cache_ttl: table.get("cache_ttl").and_then(|t| t.as_integer()).map(|t| Duration::from_secs(t.max(0) as u64)),
+            secrets,
+        })
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        self.allowed_hosts.iter().any(|pattern| host_matches(pattern, host))
+    }
+
+    /// The secrets to add to a request. Secrets are only ever sent over https, to the port of their origin.
+    fn injections<'a>(&'a self, url: &'a Url) -> impl Iterator<Item = &'a SecretInjection> {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        self.secrets.iter().filter(move |i| {
+            url.scheme() == "https" && url.port_or_known_default() == Some(i.port) && host_matches(&i.host, &host)
+        })
+    }
+}
+
+impl SecretInjection {
+    fn from_table(table: &Table) -> anyhow::Result<SecretInjection> {
+        let target = match (table.get("header").and_then(|h| h.as_str()), table.get("query").and_then(|q| q.as_str())) {
+            (Some(header), None) => InjectionTarget::Header(header.to_string()),
+            (None, Some(query)) => InjectionTarget::Query(query.to_string()),
+            _ => bail!("A secret needs either header or query"),
+        };
+        let origin = table.get("origin").and_then(|o| o.as_str()).ok_or(anyhow!("A secret needs an origin"))?;
+        let (host, port) = parse_origin(origin)?;
+        Ok(SecretInjection {
+            host,
+            port,
+            secret: table.get("secret").and_then(|s| s.as_str()).ok_or(anyhow!("A secret needs a secret name"))?.to_string(),
+            target,
+            format: table.get("format").and_then(|f| f.as_str()).unwrap_or("{}").to_string(),
+        })
+    }
+}
+
+/// Splits an `https://host[:port]` origin. The host may be a pattern like `*.example.com`.
+fn parse_origin(origin: &str) -> anyhow::Result<(String, u16)> {
+    let authority = origin.strip_prefix("https://").ok_or(anyhow!("Secrets are only sent over https, not to {}", origin))?;
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| anyhow!("Invalid port in origin {}", origin))?),
+        None => (authority, 443),
+    };
+    if host.is_empty() || host.contains(['/', '?', '#', '@', '[', ']']) {
+        bail!("Invalid origin {}, expected https://host[:port]", origin);
+    }
+    Ok((host.to_ascii_lowercase(), port))
+}
+
+/// `*.example.com` matches subdomains of example.com, other patterns match exactly.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// `/proxy?url=<url>` forwards a request to `url` on behalf of the bundle of the dashboard
+/// view sending it, as identified by its view cookie.
+pub fn register_routes(router: &mut Router) {
+    router
+        .get("/proxy", forward)
+        .post("/proxy", forward)
+        .put("/proxy", forward)
+        .patch("/proxy", forward)
+        .delete("/proxy", forward);
+}
+
+fn text_response(status: i32, message: String) -> HttpResponse {
+    HttpResponse::new(status)
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(message.into_bytes())
+}
+
+/// Adds the bundle's secrets for the url's origin to its query, and returns the headers to add.
+fn inject_secrets(rules: &ProxyRules, secrets: &Secrets, uuid: &str, url: &mut Url) -> anyhow::Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    let injections: Vec<SecretInjection> = rules.injections(url).cloned().collect();
+    for injection in &injections {
+        let secret = secrets.get(uuid, &injection.secret)?.ok_or(anyhow!("Secret {} of bundle {} is not set", injection.secret, uuid))?;
+        let value = injection.format.replace("{}", &secret);
+        match &injection.target {
+            InjectionTarget::Header(name) => headers.push((name.clone(), value)),
+            InjectionTarget::Query(name) => { url.query_pairs_mut().append_pair(name, &value); }
+        }
+    }
+    Ok(headers)
+}
+
+fn forward(system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let Some(uuid) = system_state.auth.view_bundle(request) else {
+        return Ok(text_response(403, String::from("Only bundle views can use the proxy")));
+    };
+    let uuid = uuid.as_str();
+    let rules = system_state.app_manager().get_bundle(uuid)
+        .ok_or(HttpError::NotFound(format!("Bundle not found: {}", uuid)))?
+        .proxy.clone();
+    let target = request.get_parameter("url").ok_or(HttpError::BadRequest(String::from("Missing url parameter")))?;
+    let mut url = Url::parse(target).map_err(|e| HttpError::BadRequest(format!("Invalid url {}: {}", target, e)))?;
+    if !matches!(url.scheme(), "http" | "https") || !url.username().is_empty() || url.password().is_some() {
+        return Err(HttpError::BadRequest(format!("Unsupported url: {}", target)).into());
+    }
+    let host = url.host_str().ok_or(HttpError::BadRequest(format!("Invalid url: {}", target)))?.to_ascii_lowercase();
+    // Only the origin and path are logged, the query may contain credentials
+    let label = format!("{}://{}{}", url.scheme(), host, url.path());
+    if !rules.allows(&host) {
+        warn!("Proxy request of bundle {} to {} denied, host is not allowed", uuid, label);
+        return Ok(text_response(403, format!("Host not allowed: {}", host)));
+    }
+    let proxy = &system_state.proxy;
+    if let Err(retry_after) = proxy.take_rate_limit_token(uuid, rules.rate_limit) {
+        warn!("Proxy request of bundle {} to {} rate limited", uuid, label);
+        return Ok(text_response(429, String::from("Too many proxy requests")).with_header("Retry-After", &retry_after.as_secs().max(1).to_string()));
+    }
+    let cache_key = format!("{} {}", uuid, url);
+    if request.method == RequestType::Get {
+        if let Some(response) = proxy.cached_response(&cache_key) {
+            info!("Proxy {} {} for bundle {}: {} (cached)", request.method, label, uuid, response.status);
+            return Ok(response);
+        }
+    }
+
+    let headers = inject_secrets(&rules, &system_state.secrets, uuid, &mut url)?;
+    let started = Instant::now();
+    let upstream_response = match proxy.send(request, &url, &headers, uuid, &label) {
+        Ok(response) => response,
+        Err(response) => return Ok(response),
+    };
+    let status = upstream_response.status() as i32;
+    let response_headers: Vec<(String, String)> = FORWARDED_RESPONSE_HEADERS.iter()
+        .filter_map(|name| upstream_response.header(name).map(|value| (name.to_string(), value.to_string())))
+        .collect();
+    let ttl = rules.cache_ttl.or_else(|| {
+        upstream_response.header("Cache-Control").filter(|c| !c.contains("no-store")).and_then(max_age)
+    });
+    let mut body = Vec::new();
+    upstream_response.into_reader().take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_RESPONSE_SIZE {
+        warn!("Proxy {} {} for bundle {}: response too large", request.method, label, uuid);
+        return Ok(text_response(502, String::from("Upstream response too large")));
+    }
+    info!("Proxy {} {} for bundle {}: {} ({} ms)", request.method, label, uuid, status, started.elapsed().as_millis());
+
+    if request.method == RequestType::Get && status == 200 {
+        if let Some(ttl) = ttl.filter(|ttl| !ttl.is_zero()) {
+            proxy.store_response(cache_key, CachedResponse { status, headers: response_headers.clone(), body: body.clone(), expires: Instant::now() + ttl });
+        }
+    }
+    let mut response = HttpResponse::new(status).with_body(body);
+    for (name, value) in response_headers {
+        response.set_header(&name, &value);
+    }
+    Ok(response)
+}
+
+impl Proxy {
+    pub fn new(max_cache_size: usize) -> Proxy {
+        Proxy {
+            agent: ureq::AgentBuilder::new().redirects(0).timeout(UPSTREAM_TIMEOUT).build(),
+            cache: Mutex::new(ResponseCache::default()),
+            max_cache_size,
+            rate_limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Token bucket allowing `rate_limit` requests per minute per bundle. Returns the time until
+    /// the next request is allowed if the bucket is empty.
+    fn take_rate_limit_token(&self, uuid: &str, rate_limit: u32) -> Result<(), Duration> {
+        let capacity = rate_limit as f64;
+        let mut limits = self.rate_limits.lock().unwrap_or_else(PoisonError::into_inner);
+        let (tokens, refilled) = limits.entry(uuid.to_string()).or_insert((capacity, Instant::now()));
+        *tokens = (*tokens + refilled.elapsed().as_secs_f64() * capacity / 60.0).min(capacity);
+        *refilled = Instant::now();
+        if *tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - *tokens) * 60.0 / capacity));
+        }
+        *tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Sends a request upstream. Error statuses of the upstream server are passed on, failed
+    /// requests become a 502 response. `label` is the url without its query, which may carry
+    /// injected secrets, and so would the message of a request error: only its kind is reported.
+    fn send(&self, request: &HttpRequest, url: &Url, headers: &[(String, String)], uuid: &str, label: &str) -> Result<ureq::Response, HttpResponse> {
+        let mut upstream = self.agent.request_url(&request.method.to_string(), url);
+        for name in FORWARDED_REQUEST_HEADERS {
+            if let Some(value) = request.header_value(name) {
+                upstream = upstream.set(name, value);
+            }
+        }
+        for (name, value) in headers {
+            upstream = upstream.set(name, value);
+        }
+        let result = match request.method {
+            RequestType::Post | RequestType::Put | RequestType::Patch => upstream.send_bytes(&request.body),
+            _ => upstream.call(),
+        };
+        match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+            Err(error) => {
+                warn!("Proxy {} {} for bundle {} failed: {}", request.method, label, uuid, error.kind());
+                Err(text_response(502, format!("Upstream request to {} failed: {}", label, error.kind())))
+            }
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, ResponseCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn cached_response(&self, key: &str) -> Option<HttpResponse> {
+        let cache = self.cache();
+        let cached = cache.entries.get(key).filter(|c| c.expires > Instant::now())?;
+        let mut response = HttpResponse::new(cached.status).with_body(cached.body.clone());
+        for (name, value) in &cached.headers {
+            response.set_header(name, value);
+        }
+        Some(response)
+    }
+
+    fn store_response(&self, key: String, response: CachedResponse) {
+        if response.body.len() > self.max_cache_size / 4 {
+            return;
+        }
+        let mut cache = self.cache();
+        let now = Instant::now();
+        cache.entries.remove(&key);
+        cache.entries.retain(|_, c| c.expires > now);
+        cache.size = cache.entries.values().map(|c| c.body.len()).sum();
+        // Evict the entries closest to expiring until the new response fits
+        while cache.size + response.body.len() > self.max_cache_size {
+            let next_to_expire = cache.entries.iter().min_by_key(|(_, c)| c.expires).map(|(k, _)| k.clone());
+            match next_to_expire.and_then(|key| cache.entries.remove(&key)) {
+                Some(evicted) => cache.size -= evicted.body.len(),
+                None => break,
+            }
+        }
+        cache.size += response.body.len();
+        cache.entries.insert(key, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::server::http::{Body, HttpLimits};
+
+    fn rules(manifest: &str) -> ProxyRules {
+        ProxyRules::from_table(&manifest.parse::<Table>().unwrap()).unwrap()
+    }
+
+    fn injected(rules: &ProxyRules, url: &str) -> Vec<String> {
+        rules.injections(&Url::parse(url).unwrap()).map(|i| i.secret.clone()).collect()
+    }
+
+    #[test]
+    fn secrets_are_only_injected_over_https_to_their_port() {
+        let rules = rules(r#"
+            allowed_hosts = ["api.example.com", "*.example.org"]
+            [[secrets]]
+            origin = "https://api.example.com"
+            secret = "key"
+            query = "key"
+            [[secrets]]
+            origin = "https://*.example.org:8443"
+            secret = "token"
+            header = "Authorization"
+            format = "Bearer {}"
+        "#);
+        assert_eq!(injected(&rules, "https://api.example.com/v1"), ["key"]);
+        assert_eq!(injected(&rules, "https://API.example.com:443/v1"), ["key"]);
+        assert_eq!(injected(&rules, "https://eu.example.org:8443/"), ["token"]);
+        for url in ["http://api.example.com/v1", "https://api.example.com:8443/v1", "http://eu.example.org:8443/", "https://eu.example.org/", "https://example.org:8443/"] {
+            assert!(injected(&rules, url).is_empty(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rate_limits_saturate() {
+        assert_eq!(rules("rate_limit = 4294967296").rate_limit, u32::MAX);
+        assert_eq!(rules("rate_limit = -5").rate_limit, 1);
+        assert_eq!(rules("").rate_limit, DEFAULT_RATE_LIMIT);
+        assert!(Proxy::new(1024).take_rate_limit_token("clock", u32::MAX).is_ok());
+    }
+
+    #[test]
+    fn rate_limits_are_kept_per_bundle() {
+        let proxy = Proxy::new(1024);
+        assert!(proxy.take_rate_limit_token("clock", 2).is_ok());
+        assert!(proxy.take_rate_limit_token("clock", 2).is_ok());
+        let retry_after = proxy.take_rate_limit_token("clock", 2).unwrap_err();
+        assert!(retry_after > Duration::from_secs(25) && retry_after <= Duration::from_secs(30), "{:?}", retry_after);
+        assert!(proxy.take_rate_limit_token("weather", 2).is_ok());
+        assert!(Proxy::new(1024).take_rate_limit_token("clock", 2).is_ok());
+    }
+
+    #[test]
+    fn cached_responses_are_evicted_closest_to_expiring() {
+        let proxy = Proxy::new(1000);
+        let response = |body: usize, ttl: u64| CachedResponse {
+            status: 200,
+            headers: vec![(String::from("Content-Type"), String::from("application/json"))],
+            body: vec![b'x'; body],
+            expires: Instant::now() + Duration::from_secs(ttl),
+        };
+        proxy.store_response(String::from("short"), response(250, 10));
+        proxy.store_response(String::from("long"), response(250, 100));
+        proxy.store_response(String::from("too large"), response(300, 100));
+        assert!(proxy.cached_response("too large").is_none());
+        proxy.store_response(String::from("new"), response(250, 50));
+        proxy.store_response(String::from("newer"), response(250, 50));
+        assert!(proxy.cached_response("short").is_some());
+        proxy.store_response(String::from("newest"), response(250, 50));
+        assert!(proxy.cached_response("short").is_none());
+        assert_eq!(proxy.cached_response("long").unwrap().header("content-type").map(|v| v.as_str()), Some("application/json"));
+        assert!(proxy.cached_response("newest").is_some());
+        assert_eq!(proxy.cache().size, 1000);
+    }
+
+    #[test]
+    fn failed_requests_do_not_reveal_injected_secrets() {
+        let path = std::env::temp_dir().join(format!("nemoscene-proxy-secrets-test-{}", std::process::id()));
+        let secrets = Secrets::new(path.clone());
+        secrets.set("clock", "key", "s3cret-value").unwrap();
+        let rules = rules(r#"
+            allowed_hosts = ["127.0.0.1"]
+            [[secrets]]
+            origin = "https://127.0.0.1:9"
+            secret = "key"
+            query = "api_key"
+        "#);
+        // Nothing listens on the discard port, so the connection is refused
+        let mut url = Url::parse("https://127.0.0.1:9/v1/time?zone=utc").unwrap();
+        assert!(inject_secrets(&rules, &secrets, "clock", &mut url).unwrap().is_empty());
+        assert!(url.as_str().contains("s3cret-value"));
+        let request = HttpRequest::read_from(&mut &b"GET /proxy HTTP/1.1\r\n\r\n"[..], &HttpLimits::default()).unwrap();
+        let response = Proxy::new(1024).send(&request, &url, &[], "clock", "https://127.0.0.1/v1/time").unwrap_err();
+        assert_eq!(response.status, 502);
+        let Body::Bytes(body) = &response.body else { panic!("unexpected body") };
+        let body = String::from_utf8_lossy(body);
+        assert!(body.starts_with("Upstream request to https://127.0.0.1/v1/time failed"), "{}", body);
+        assert!(!body.contains("s3cret"), "{}", body);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn secret_origins_must_be_https() {
+        assert_eq!(parse_origin("https://api.example.com").unwrap(), (String::from("api.example.com"), 443));
+        assert_eq!(parse_origin("https://*.Example.com:8443").unwrap(), (String::from("*.example.com"), 8443));
+        for origin in ["http://api.example.com", "api.example.com", "https://", "https://host:port", "https://host/path", "https://user@host", "https://[::1]:443"] {
+            assert!(parse_origin(origin).is_err(), "{}", origin);
+        }
+        let table = "host = \"api.example.com\"\nsecret = \"key\"\nquery = \"key\"".parse::<Table>().unwrap();
+        assert!(SecretInjection::from_table(&table).is_err());
+    }
+}
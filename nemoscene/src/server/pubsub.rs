@@ -0,0 +1,145 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Topic namespaces only the server publishes to: `events.*` (system events),
+/// `data.*` (data providers) and `tasks.*` (scheduled task results).
+const RESERVED_PREFIXES: [&str; 3] = ["events", "data", "tasks"];
+
+/// Whether a topic belongs to a server-side publisher, so clients must not publish to it.
+pub fn is_reserved(topic: &str) -> bool {
+    RESERVED_PREFIXES.iter().any(|prefix| {
+        topic.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// A message published on a topic.
+#[derive(Debug, Clone, Serialize)]
+pub struct PubSubMessage {
+    pub topic: String,
+    pub data: Value,
+}
+
+struct Subscriber {
+    topics: HashSet<String>,
+    sender: Sender<PubSubMessage>,
+}
+
+/// Topic-based publish/subscribe between Rust services and widgets connected over WebSocket.
+/// Topics are matched exactly.
+pub struct PubSub {
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+    next_id: AtomicU64,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            subscribers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Delivers a message to every subscriber of the topic and returns the number of recipients.
+    pub fn publish(&self, topic: &str, data: Value) -> usize {
+        self.publish_from(None, topic, data)
+    }
+
+    /// Like `publish`, but does not echo the message back to the publishing subscriber.
+    pub fn publish_from(&self, publisher: Option<u64>, topic: &str, data: Value) -> usize {
+        let message = PubSubMessage { topic: topic.to_string(), data };
+        let mut subscribers = self.lock();
+        // Subscribers whose receiving end is gone are dropped on the next publish.
+        subscribers.retain(|id, subscriber| {
+            !subscriber.topics.contains(topic) || Some(*id) == publisher || subscriber.sender.send(message.clone()).is_ok()
+        });
+        subscribers.iter().filter(|(id, s)| Some(**id) != publisher && s.topics.contains(topic)).count()
+    }
+
+    /// Registers a subscriber to the given topics. More topics can be added to the subscription later.
+    pub fn subscribe(self: &Arc<Self>, topics: &[&str]) -> Subscription {
+        let (sender, receiver) = mpsc::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(id, Subscriber {
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            sender,
+        });
+        Subscription { id, pubsub: self.clone(), receiver }
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Subscriber>> {
+        self.subscribers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Receives the messages of the subscribed topics. Dropping it unsubscribes from all topics.
+pub struct Subscription {
+    id: u64,
+    pubsub: Arc<PubSub>,
+    receiver: Receiver<PubSubMessage>,
+}
+
+impl Subscription {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn add_topic(&self, topic: &str) {
+        if let Some(subscriber) = self.pubsub.lock().get_mut(&self.id) {
+            subscriber.topics.insert(topic.to_string());
+        }
+    }
+
+    pub fn remove_topic(&self, topic: &str) {
+        if let Some(subscriber) = self.pubsub.lock().get_mut(&self.id) {
+            subscriber.topics.remove(topic);
+        }
+    }
+
+    pub fn recv(&self) -> Option<PubSubMessage> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<PubSubMessage> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<PubSubMessage> {
+        match self.receiver.try_recv() {
+            Ok(message) => Some(message),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.pubsub.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_namespaces_are_reserved() {
+        for topic in ["events", "events.bundle_loaded", "data.calendar", "tasks.clock.sync"] {
+            assert!(is_reserved(topic), "{}", topic);
+        }
+        for topic in ["calendar", "eventsource", "database.changed", "my.tasks"] {
+            assert!(!is_reserved(topic), "{}", topic);
+        }
+    }
+}
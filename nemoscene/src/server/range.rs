@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::SystemTime;
+use crate::server::cache::FileValidators;
+use crate::server::http::{HttpRequest, HttpResponse};
+
+/// Requests with more ranges than this are answered with the whole file.
+const MAX_RANGES: usize = 16;
+
+const MULTIPART_BOUNDARY: &str = "nemoscene-byteranges";
+
+const COPY_BUFFER_SIZE: u64 = 64 * 1024;
+
+/// An inclusive byte range within a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header: respond with the whole file.
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// Evaluates the `Range` and `If-Range` headers of a request for a file of `length` bytes.
+    /// Malformed headers and ranges of other units are ignored.
+    pub fn from_request(request: &HttpRequest, validators: &FileValidators, length: u64) -> RangeRequest {
+        let range = match request.header_value("Range") {
+            Some(range) => range,
+            None => return RangeRequest::Full,
+        };
+        if let Some(if_range) = request.header_value("If-Range") {
+            if !if_range_matches(if_range.trim(), validators) {
+                return RangeRequest::Full;
+            }
+        }
+        Self::parse(range, length)
+    }
+
+    pub fn parse(range: &str, length: u64) -> RangeRequest {
+        let specs = match range.trim().strip_prefix("bytes=") {
+            Some(specs) => specs,
+            None => return RangeRequest::Full,
+        };
+        let mut ranges = Vec::new();
+        for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let (start, end) = match spec.split_once('-') {
+                Some(bounds) => bounds,
+                None => return RangeRequest::Full,
+            };
+            let range = match (start.trim(), end.trim()) {
+                ("", suffix) => match suffix.parse::<u64>() {
+                    Ok(0) => continue,
+                    Ok(suffix) if length > 0 => ByteRange { start: length.saturating_sub(suffix), end: length - 1 },
+                    Ok(_) => continue,
+                    Err(_) => return RangeRequest::Full,
+                },
+                (start, end) => {
+                    let start = match start.parse::<u64>() {
+                        Ok(start) => start,
+                        Err(_) => return RangeRequest::Full,
+                    };
+                    let end = match end {
+                        "" => length.saturating_sub(1),
+                        end => match end.parse::<u64>() {
+                            Ok(end) if end >= start => end.min(length.saturating_sub(1)),
+                            _ => return RangeRequest::Full,
+                        },
+                    };
+                    if start >= length {
+                        continue;
+                    }
+                    ByteRange { start, end }
+                }
+            };
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            return RangeRequest::Unsatisfiable;
+        }
+        if ranges.len() > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        RangeRequest::Partial(coalesce(ranges))
+    }
+}
+
+/// `If-Range` holds either a strong ETag or an HTTP date; weak tags never match.
+fn if_range_matches(if_range: &str, validators: &FileValidators) -> bool {
+    if if_range.starts_with('"') {
+        return if_range == validators.etag;
+    }
+    match httpdate::parse_http_date(if_range) {
+        Ok(date) => same_second(validators.last_modified, date),
+        Err(_) => false,
+    }
+}
+
+/// Sorts ranges and merges overlapping or adjacent ones.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Responds with the requested ranges of a file: a single range as `206 Partial Content`
+/// with a `Content-Range`, several ranges as a `multipart/byteranges` body.
+pub fn partial_response(content_type: &str, path: &Path, ranges: &[ByteRange]) -> anyhow::Result<HttpResponse> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    if let [range] = ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        return Ok(HttpResponse::new(206)
+            .with_header("Content-Type", content_type)
+            .with_header("Content-Range", &range.content_range(length))
+            .with_file(file, range.len()));
+    }
+    let content_type = content_type.to_string();
+    let parts: Vec<ByteRange> = ranges.to_vec();
+    let mut index = 0;
+    let mut remaining = 0;
+    let mut finished = false;
+    let chunks = std::iter::from_fn(move || {
+        if finished {
+            return None;
+        }
+        if remaining > 0 {
+            let mut chunk = vec![0u8; remaining.min(COPY_BUFFER_SIZE) as usize];
+            return Some(file.read_exact(&mut chunk).map(|_| {
+                remaining -= chunk.len() as u64;
+                chunk
+            }).map_err(anyhow::Error::from));
+        }
+        let Some(range) = parts.get(index) else {
+            finished = true;
+            return Some(Ok(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY).into_bytes()));
+        };
+        index += 1;
+        remaining = range.len();
+        Some(file.seek(SeekFrom::Start(range.start)).map(|_| format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            MULTIPART_BOUNDARY, content_type, range.content_range(length)
+        ).into_bytes()).map_err(anyhow::Error::from))
+    });
+    let mut response = HttpResponse::stream(&format!("multipart/byteranges; boundary={}", MULTIPART_BOUNDARY), chunks);
+    response.status = 206;
+    Ok(response)
+}
+
+pub fn unsatisfiable_response(length: u64) -> HttpResponse {
+    HttpResponse::new(416).with_header("Content-Range", &format!("bytes */{}", length))
+}
+
+/// Modification times are compared at the one second resolution of HTTP dates.
+fn same_second(a: SystemTime, b: SystemTime) -> bool {
+    httpdate::fmt_http_date(a) == httpdate::fmt_http_date(b)
+}
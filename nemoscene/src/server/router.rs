@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use log::debug;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse, RequestType};
+use crate::system_state::SystemState;
+
+pub type Handler = Box<dyn Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync>;
+
+pub type Next<'a> = &'a dyn Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse>;
+
+/// Wraps request handling. Middleware can inspect or modify the request before calling `next`,
+/// answer the request itself, or modify the response returned by `next`.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, system_state: &SystemState, request: &HttpRequest, next: Next) -> anyhow::Result<HttpResponse>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+    Rest(String),
+}
+
+/// A route pattern such as `/bundle/:uuid/*path`. `:name` captures one path segment,
+/// `*name` captures the remainder of the path and must be the last segment.
+#[derive(Debug, Clone)]
+pub struct RoutePattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    pub fn parse(pattern: &str) -> RoutePattern {
+        let segments = pattern.split('/').filter(|s| !s.is_empty()).map(|s| {
+            if let Some(name) = s.strip_prefix(':') {
+                Segment::Parameter(name.to_string())
+            } else if let Some(name) = s.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(s.to_string())
+            }
+        }).collect();
+        RoutePattern { source: pattern.to_string(), segments }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, route: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+        let mut parameters = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => if parts.get(i) != Some(&literal.as_str()) {
+                    return None;
+                },
+                Segment::Parameter(name) => {
+                    parameters.insert(name.clone(), url_escape::decode(parts.get(i)?).to_string());
+                }
+                Segment::Rest(name) => {
+                    let rest = parts.get(i..).map(|p| p.join("/")).unwrap_or_default();
+                    parameters.insert(name.clone(), url_escape::decode(&rest).to_string());
+                    return Some(parameters);
+                }
+            }
+        }
+        if parts.len() == self.segments.len() {
+            Some(parameters)
+        } else {
+            None
+        }
+    }
+}
+
+struct Route {
+    method: RequestType,
+    pattern: RoutePattern,
+    handler: Handler,
+}
+
+/// Dispatches requests to handlers by method and route pattern, through a chain of middleware.
+/// Routes are matched in registration order.
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn route<F>(&mut self, method: RequestType, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: RoutePattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route(RequestType::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route(RequestType::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route(RequestType::Put, pattern, handler)
+    }
+
+    pub fn patch<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route(RequestType::Patch, pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&SystemState, &HttpRequest) -> anyhow::Result<HttpResponse> + Send + Sync + 'static,
+    {
+        self.route(RequestType::Delete, pattern, handler)
+    }
+
+    /// Adds middleware. Middleware added first runs outermost.
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// The pattern of the first route matching the request's path, regardless of method.
+    pub fn route_label(&self, request: &HttpRequest) -> &str {
+        self.routes.iter().find(|r| r.pattern.matches(&request.route).is_some()).map_or("unmatched", |r| r.pattern.as_str())
+    }
+
+    pub fn handle(&self, system_state: &SystemState, request: &HttpRequest) -> HttpResponse {
+        self.run_middleware(0, system_state, request).unwrap_or_else(|error| HttpResponse::from_error(&error))
+    }
+
+    fn run_middleware(&self, index: usize, system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+        match self.middleware.get(index) {
+            Some(middleware) => middleware.handle(system_state, request, &|system_state, request| self.run_middleware(index + 1, system_state, request)),
+            None => self.dispatch(system_state, request),
+        }
+    }
+
+    /// HEAD requests are answered by GET routes, and OPTIONS requests are answered
+    /// automatically with the methods allowed for the route.
+    fn dispatch(&self, system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+        let mut allowed: Vec<RequestType> = Vec::new();
+        for route in &self.routes {
+            if let Some(parameters) = route.pattern.matches(&request.route) {
+                if route.method == request.method || (request.method == RequestType::Head && route.method == RequestType::Get) {
+                    let mut request = request.clone();
+                    request.route_parameters = parameters;
+                    return (route.handler)(system_state, &request);
+                }
+                allowed.push(route.method);
+                if route.method == RequestType::Get {
+                    allowed.push(RequestType::Head);
+                }
+            }
+        }
+        if allowed.is_empty() {
+            return Ok(HttpResponse::new(404).with_header("Content-Type", "text/plain").with_body(format!("Not found: {}", request.route).into_bytes()));
+        }
+        allowed.push(RequestType::Options);
+        let allowed: Vec<RequestType> = RequestType::ALL.into_iter().filter(|m| allowed.contains(m)).collect();
+        let allow = RequestType::allow_header(&allowed);
+        if request.method == RequestType::Options {
+            Ok(HttpResponse::new(204).with_header("Allow", &allow))
+        } else {
+            Ok(HttpResponse::new(405).with_header("Allow", &allow))
+        }
+    }
+}
+
+/// Logs every request with its response status.
+pub struct LoggingMiddleware;
+
+impl Middleware for LoggingMiddleware {
+    fn handle(&self, system_state: &SystemState, request: &HttpRequest, next: Next) -> anyhow::Result<HttpResponse> {
+        let response = next(system_state, request);
+        let status = match &response {
+            Ok(response) => response.status,
+            Err(error) => error.downcast_ref::<HttpError>().map_or(500, |e| e.into()),
+        };
+        debug!("{} {} {}", request.method, request.route, status);
+        response
+    }
+}
+
+/// Adds CORS headers to the given routes so pages served from other origins can call them,
+/// and answers preflight requests with the methods allowed for the route. Other routes
+/// get no CORS headers, so browsers keep them same-origin.
+pub struct CorsMiddleware {
+    pub allowed_origin: String,
+    pub routes: &'static [&'static str],
+}
+
+impl Middleware for CorsMiddleware {
+    fn handle(&self, system_state: &SystemState, request: &HttpRequest, next: Next) -> anyhow::Result<HttpResponse> {
+        if !self.routes.contains(&request.route.as_str()) {
+            return next(system_state, request);
+        }
+        let mut response = next(system_state, request).unwrap_or_else(|error| HttpResponse::from_error(&error));
+        response.set_header("Access-Control-Allow-Origin", &self.allowed_origin);
+        if request.method == RequestType::Options && request.header_value("Access-Control-Request-Method").is_some() {
+            if let Some(allow) = response.header("Allow").cloned() {
+                response.set_header("Access-Control-Allow-Methods", &allow);
+            }
+            if let Some(headers) = request.header_value("Access-Control-Request-Headers") {
+                response.set_header("Access-Control-Allow-Headers", headers);
+            }
+            response.set_header("Access-Control-Max-Age", "86400");
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use toml::{Table, Value};
+
+/// The table holding the secrets of data providers. It is not a valid bundle uuid, so bundles
+/// cannot have them injected into their proxied requests.
+pub const PROVIDER_SECRETS: &str = "@providers";
+
+/// Secrets stored in `secrets` in the data root, one table per bundle. The file is kept outside
+/// the configuration folder, so secrets are never served by the configuration endpoints.
+pub struct Secrets {
+    path: PathBuf,
+    /// Serializes writes to the secrets file.
+    write_lock: Mutex<()>,
+}
+
+impl Secrets {
+    pub fn new(path: PathBuf) -> Secrets {
+        Secrets { path, write_lock: Mutex::new(()) }
+    }
+
+    fn read(&self) -> anyhow::Result<Table> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(content.parse::<Table>()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Table::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, secrets: &Table) -> anyhow::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temporary)?;
+        file.write_all(toml::to_string(secrets)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temporary, &self.path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, bundle: &str, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.read()?.get(bundle).and_then(|b| b.get(name)).and_then(|s| s.as_str()).map(|s| s.to_string()))
+    }
+
+    /// Names of the stored secrets per bundle. Values are never exposed.
+    pub fn names(&self) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+        Ok(self.read()?.iter()
+            .filter_map(|(bundle, secrets)| Some((bundle.clone(), secrets.as_table()?.keys().cloned().collect())))
+            .collect())
+    }
+
+    pub fn set(&self, bundle: &str, name: &str, value: &str) -> anyhow::Result<()> {
+        let _lock = self.lock();
+        let mut secrets = self.read()?;
+        let bundle_secrets = secrets.entry(bundle).or_insert(Value::Table(Table::new()));
+        if let Value::Table(bundle_secrets) = bundle_secrets {
+            bundle_secrets.insert(name.to_string(), Value::String(value.to_string()));
+        }
+        self.write(&secrets)
+    }
+
+    /// Removes a secret. Returns false if it did not exist.
+    pub fn remove(&self, bundle: &str, name: &str) -> anyhow::Result<bool> {
+        let _lock = self.lock();
+        let mut secrets = self.read()?;
+        let removed = secrets.get_mut(bundle).and_then(|b| b.as_table_mut()).and_then(|b| b.remove(name)).is_some();
+        if removed {
+            self.write(&secrets)?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    #[test]
+    fn secrets_are_stored_privately_per_bundle() {
+        let path = std::env::temp_dir().join(format!("nemoscene-secrets-test-{}", process::id()));
+        let secrets = Secrets::new(path.clone());
+        assert_eq!(secrets.get("clock", "api_key").unwrap(), None);
+        secrets.set("clock", "api_key", "s3cret").unwrap();
+        secrets.set(PROVIDER_SECRETS, "google", "k3y").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(secrets.get("clock", "api_key").unwrap().as_deref(), Some("s3cret"));
+        assert_eq!(secrets.get("weather", "api_key").unwrap(), None);
+        assert_eq!(secrets.names().unwrap()["clock"], ["api_key"]);
+        assert!(secrets.remove("clock", "api_key").unwrap());
+        assert!(!secrets.remove("clock", "api_key").unwrap());
+        assert_eq!(secrets.get(PROVIDER_SECRETS, "google").unwrap().as_deref(), Some("k3y"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,12 +1,31 @@
 use std::{
     boxed::Box,
     sync::{mpsc, Arc, Mutex},
+    sync::atomic::{AtomicUsize, Ordering},
     thread,
 };
 
+/// Number of queued jobs and of workers running a job.
+#[derive(Default)]
+pub struct PoolStatistics {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+}
+
+impl PoolStatistics {
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Option<mpsc::Sender<Job>>,
+    statistics: Arc<PoolStatistics>,
 }
 
 impl ThreadPool {
@@ -16,24 +35,32 @@ impl ThreadPool {
 
         let receiver = Arc::new(Mutex::new(receiver));
 
+        let statistics = Arc::new(PoolStatistics::default());
+
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&statistics)));
         }
 
         ThreadPool {
             workers,
             sender: Some(sender),
+            statistics,
         }
     }
 
+    pub fn statistics(&self) -> Arc<PoolStatistics> {
+        Arc::clone(&self.statistics)
+    }
+
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
+        self.statistics.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
 }
@@ -58,13 +85,16 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, statistics: Arc<PoolStatistics>) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv();
 
             match message {
                 Ok(job) => {
+                    statistics.queued.fetch_sub(1, Ordering::Relaxed);
+                    statistics.active.fetch_add(1, Ordering::Relaxed);
                     job();
+                    statistics.active.fetch_sub(1, Ordering::Relaxed);
                 }
                 Err(_) => {
                     break;
@@ -0,0 +1,78 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::anyhow;
+use log::info;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// A client connection, either plain TCP or TLS over TCP.
+pub trait Connection: Read + Write + Send + 'static {
+    /// The underlying socket, used to set timeouts.
+    fn socket(&self) -> &TcpStream;
+
+    fn is_secure(&self) -> bool;
+}
+
+impl Connection for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    fn is_secure(&self) -> bool {
+        false
+    }
+}
+
+impl Connection for StreamOwned<ServerConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn is_secure(&self) -> bool {
+        true
+    }
+}
+
+/// Loads the certificate chain and private key from PEM files. If neither file exists,
+/// a self-signed certificate for `localhost` is generated and saved first.
+pub fn load_server_config(certificate_path: &Path, key_path: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    if !certificate_path.exists() && !key_path.exists() {
+        generate_self_signed(certificate_path, key_path)?;
+    }
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(certificate_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates found in {}", certificate_path.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or(anyhow!("No private key found in {}", key_path.display()))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    Ok(Arc::new(config))
+}
+
+fn generate_self_signed(certificate_path: &Path, key_path: &Path) -> anyhow::Result<()> {
+    info!("Generating self-signed TLS certificate {}", certificate_path.display());
+    let mut names = vec![String::from("localhost")];
+    if let Ok(hostname) = fs::read_to_string("/etc/hostname") {
+        let hostname = hostname.trim();
+        if !hostname.is_empty() && hostname != "localhost" {
+            names.push(hostname.to_string());
+        }
+    }
+    let certificate = rcgen::generate_simple_self_signed(names)?;
+    for path in [certificate_path, key_path] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(certificate_path, certificate.serialize_pem()?)?;
+    // The private key must only be readable by the user running the server.
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(key_path)?
+        .write_all(certificate.serialize_private_key_pem().as_bytes())?;
+    Ok(())
+}
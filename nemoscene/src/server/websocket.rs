@@ -0,0 +1,107 @@
+use std::io;
+use std::time::Duration;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tungstenite::{Error, Message, WebSocket};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use crate::server;
+use crate::server::http::{HttpError, HttpRequest, HttpResponse};
+use crate::server::pubsub::{self, PubSub, Subscription};
+use crate::server::tls::Connection;
+use crate::system_state::SystemState;
+
+/// How often a session checks for published messages while waiting for client frames.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Messages sent by widgets, e.g. `{"type": "subscribe", "topic": "calendar"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    Publish {
+        topic: String,
+        #[serde(default)]
+        data: Value,
+    },
+}
+
+fn header_has_token(request: &HttpRequest, name: &str, token: &str) -> bool {
+    request.header.get_all(name).iter()
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Answers a WebSocket upgrade request with `101 Switching Protocols`. The connection
+/// handler hands the stream to `run_session` once the response is written.
+pub fn upgrade(_system_state: &SystemState, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    if !header_has_token(request, "Upgrade", "websocket") || !header_has_token(request, "Connection", "upgrade") {
+        return Ok(HttpResponse::new(426)
+            .with_header("Upgrade", "websocket")
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"WebSocket upgrade required".to_vec()));
+    }
+    if request.header_value("Sec-WebSocket-Version") != Some("13") {
+        return Ok(HttpResponse::new(426).with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = request.header_value("Sec-WebSocket-Key").ok_or(HttpError::BadRequest(String::from("Missing Sec-WebSocket-Key")))?;
+    Ok(HttpResponse::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &derive_accept_key(key.trim().as_bytes())))
+}
+
+/// Relays messages between one widget and the pub/sub hub until either side closes
+/// the connection or the server stops.
+pub fn run_session<S: Connection>(system_state: &SystemState, stream: S) -> anyhow::Result<()> {
+    let pubsub = &system_state.pubsub;
+    stream.socket().set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    let subscription = pubsub.subscribe(&[]);
+    debug!("WebSocket session {} opened", subscription.id());
+    let mut closing = false;
+    loop {
+        if !closing && !server::is_running(system_state) {
+            socket.close(None)?;
+            closing = true;
+        }
+        match socket.read() {
+            Ok(Message::Text(text)) => handle_message(pubsub, &subscription, &mut socket, &text)?,
+            Ok(_) => {}
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => break,
+            Err(e) => {
+                debug!("WebSocket session {} failed: {}", subscription.id(), e);
+                break;
+            }
+        }
+        if !closing {
+            while let Some(message) = subscription.try_recv() {
+                socket.send(Message::Text(serde_json::to_string(&message)?))?;
+            }
+        }
+    }
+    debug!("WebSocket session {} closed", subscription.id());
+    Ok(())
+}
+
+fn handle_message<S: Connection>(pubsub: &PubSub, subscription: &Subscription, socket: &mut WebSocket<S>, text: &str) -> anyhow::Result<()> {
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Subscribe { topic }) => subscription.add_topic(&topic),
+        Ok(ClientMessage::Unsubscribe { topic }) => subscription.remove_topic(&topic),
+        Ok(ClientMessage::Publish { topic, .. }) if pubsub::is_reserved(&topic) => {
+            warn!("Rejected WebSocket publish to reserved topic {}", topic);
+            socket.send(Message::Text(json!({ "type": "error", "message": format!("Topic {} is reserved for the server", topic) }).to_string()))?;
+        }
+        Ok(ClientMessage::Publish { topic, data }) => {
+            pubsub.publish_from(Some(subscription.id()), &topic, data);
+        }
+        Err(e) => {
+            warn!("Invalid WebSocket message: {}", e);
+            socket.send(Message::Text(json!({ "type": "error", "message": e.to_string() }).to_string()))?;
+        }
+    }
+    Ok(())
+}
@@ -1,49 +1,268 @@
 use std::{process, thread};
-use std::sync::{Arc, Mutex, MutexGuard};
-use log::info;
-use once_cell::sync::Lazy;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::AtomicBool;
+use std::thread::JoinHandle;
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use crate::app::manager::AppManager;
+use crate::cli::Arguments;
 use crate::configuration::ConfigurationRegistry;
+use crate::control;
 use crate::dashboard::{Dashboard, DashboardMessage, Point};
-use crate::dashboard::view::ViewParameters;
+use crate::dashboard::view::{ViewContext, ViewParameters};
+use crate::events::{Event, EventBus};
+use crate::providers::ProviderRegistry;
+use crate::scheduler::Scheduler;
+use crate::server;
+use crate::server::auth::Authenticator;
+use crate::server::cache::{EtagCache, MAX_CACHE_SIZE};
+use crate::server::compression::CompressionCache;
+use crate::server::metrics::Metrics;
+use crate::server::proxy::Proxy;
+use crate::server::pubsub::PubSub;
 use crate::server::run_server;
+use crate::server::secrets::Secrets;
 
-pub static SYSTEM_STATE: Lazy<Arc<Mutex<SystemState>>> = Lazy::new(|| {
-    let mut system_state = SystemState {
-        configuration: ConfigurationRegistry::new(),
-        dashboard: Dashboard::new(),
-        app_manager: AppManager::new(),
-    };
-    system_state.init();
-    Arc::new(Mutex::new(system_state))
-});
-
-#[macro_export]
-macro_rules! get_system_state {
-    () => {
-        crate::system_state::SYSTEM_STATE.clone().lock().expect("System state mutex poisoned")
-    };
+/// Lets any thread ask the main thread to exit the process with a status code.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<(Mutex<Option<i32>>, Condvar)>);
+
+impl Shutdown {
+    /// Requests the exit. The first requested status code wins.
+    pub fn request(&self, code: i32) {
+        let (lock, condvar) = &*self.0;
+        let mut status = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if status.is_none() {
+            *status = Some(code);
+        }
+        condvar.notify_all();
+    }
+
+    /// Blocks until an exit is requested and returns the status code.
+    pub fn wait(&self) -> i32 {
+        let (lock, condvar) = &*self.0;
+        let status = condvar.wait_while(lock.lock().unwrap_or_else(PoisonError::into_inner), |status| status.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        status.unwrap()
+    }
 }
 
+/// Shared services of the running instance. Every service is locked independently,
+/// so cloning the state and handing it to another thread is cheap.
+#[derive(Clone)]
 pub struct SystemState {
-    pub configuration: ConfigurationRegistry,
-    pub dashboard: Dashboard,
-    pub app_manager: AppManager,
+    pub arguments: Arc<Arguments>,
+    configuration: Arc<RwLock<ConfigurationRegistry>>,
+    app_manager: Arc<RwLock<AppManager>>,
+    pub dashboard: Arc<Dashboard>,
+    pub pubsub: Arc<PubSub>,
+    pub events: Arc<EventBus>,
+    pub auth: Arc<Authenticator>,
+    pub metrics: Arc<Metrics>,
+    pub scheduler: Arc<Scheduler>,
+    pub providers: Arc<ProviderRegistry>,
+    pub secrets: Arc<Secrets>,
+    pub etags: Arc<EtagCache>,
+    pub compression: Arc<CompressionCache>,
+    pub proxy: Arc<Proxy>,
+    pub shutdown: Shutdown,
+    /// Whether the server accepts new connections, cleared by `stop`.
+    pub running: Arc<AtomicBool>,
+    server_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    control_socket: Arc<Mutex<Option<PathBuf>>>,
 }
 
 impl SystemState {
-    pub fn init(&mut self) {
+    /// Loads the configuration, starts the dashboard and loads bundles, in that order,
+    /// then starts the server with a handle to the resulting state.
+    pub fn init(arguments: Arguments) -> anyhow::Result<SystemState> {
         info!("Initializing system");
-        self.configuration.load_all("data/configuration").expect("Cannot load system configuration base");
-        self.dashboard.init(&self.configuration).expect("Cannot initialize Dashboard");
-        self.app_manager.init(&mut self.configuration);
-        thread::spawn(run_server);
+        let arguments = Arc::new(arguments);
+        let mut configuration = ConfigurationRegistry::new();
+        Self::load_configuration(&arguments, &mut configuration)?;
+        let headless = arguments.headless || configuration.get_base(&arguments.data_path("configuration/nemoscene")).and_then(|b| b.get_bool("headless")).unwrap_or(false);
+        let pubsub = Arc::new(PubSub::new());
+        let events = Arc::new(EventBus::new());
+        events.bridge_to_log();
+        events.bridge_to_pubsub(pubsub.clone());
+        let metrics = Arc::new(Metrics::new());
+        metrics.watch_events(&events);
+        let auth = Arc::new(Authenticator::new());
+        let shutdown = Shutdown::default();
+        let mut dashboard = Dashboard::new(shutdown.clone());
+        let view_context = ViewContext { events: events.clone(), auth: auth.clone(), arguments: arguments.clone() };
+        dashboard.init(&configuration, headless, view_context)?;
+        let mut app_manager = AppManager::new(PathBuf::from(arguments.data_path("bundles")));
+        app_manager.init(&mut configuration);
+        for bundle in app_manager.bundles() {
+            events.publish(Event::BundleLoaded { uuid: bundle.uuid.clone() });
+        }
+        let scheduler = Arc::new(Scheduler::new(pubsub.clone(), events.clone(), PathBuf::from(arguments.data_path("tasks"))));
+        scheduler.load(&app_manager, Self::coordinates(&arguments, &configuration));
+        scheduler.start();
+        let secrets = Arc::new(Secrets::new(PathBuf::from(arguments.data_path("secrets"))));
+        let providers = Arc::new(ProviderRegistry::new(pubsub.clone(), secrets.clone(), &arguments));
+        providers.load(&configuration);
+        providers.start();
+
+        let system_state = SystemState {
+            arguments,
+            configuration: Arc::new(RwLock::new(configuration)),
+            app_manager: Arc::new(RwLock::new(app_manager)),
+            dashboard: Arc::new(dashboard),
+            pubsub,
+            events,
+            auth,
+            metrics,
+            scheduler,
+            providers,
+            secrets,
+            etags: Arc::new(EtagCache::default()),
+            compression: Arc::new(CompressionCache::new(MAX_CACHE_SIZE)),
+            proxy: Arc::new(Proxy::new(MAX_CACHE_SIZE)),
+            shutdown,
+            running: Arc::new(AtomicBool::new(false)),
+            server_thread: Arc::new(Mutex::new(None)),
+            control_socket: Arc::new(Mutex::new(None)),
+        };
+        let listeners = server::bind_listeners(&system_state)?;
+        let server_state = system_state.clone();
+        *system_state.server_thread.lock().unwrap() = Some(thread::spawn(move || run_server(server_state, listeners)));
+        match control::start(system_state.clone()) {
+            Ok(path) => *system_state.control_socket.lock().unwrap() = Some(path),
+            Err(error) => error!("Cannot start control socket: {}", error),
+        }
+        system_state.metrics.set_ready(true);
+        Ok(system_state)
+    }
+
+    fn load_configuration(arguments: &Arguments, configuration: &mut ConfigurationRegistry) -> anyhow::Result<()> {
+        configuration.load_all(&arguments.data_path("configuration"))?;
+        for (base, key, value) in arguments.parse_config_overrides()? {
+            match configuration.get_base_mut(&base) {
+                Some(base) => base.override_value(&key, value),
+                None => error!("Cannot override {}: configuration base {} not found", key, base),
+            }
+        }
+        Ok(())
+    }
+
+    /// Latitude and longitude from the `nemoscene` configuration base, used for sunrise and sunset tasks.
+    fn coordinates(arguments: &Arguments, configuration: &ConfigurationRegistry) -> Option<(f64, f64)> {
+        let base = configuration.get_base(&arguments.data_path("configuration/nemoscene"))?;
+        Some((base.get_f64("latitude")?, base.get_f64("longitude")?))
+    }
+
+    // A panic while holding one of the locks must not take down the other services,
+    // so poisoned locks are recovered instead of propagated.
+
+    pub fn configuration(&self) -> RwLockReadGuard<'_, ConfigurationRegistry> {
+        self.configuration.read().unwrap_or_else(PoisonError::into_inner)
     }
 
-    pub fn shutdown() -> ! {
-        process::exit(0);
-        loop {};
+    pub fn configuration_mut(&self) -> RwLockWriteGuard<'_, ConfigurationRegistry> {
+        self.configuration.write().unwrap_or_else(PoisonError::into_inner)
     }
 
+    pub fn app_manager(&self) -> RwLockReadGuard<'_, AppManager> {
+        self.app_manager.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn app_manager_mut(&self) -> RwLockWriteGuard<'_, AppManager> {
+        self.app_manager.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Flushes pending configuration changes, then reloads all configuration bases and bundles from disk.
+    /// The configuration lock is always taken before the app manager lock.
+    pub fn reload(&self) {
+        info!("Reloading configuration and bundles");
+        self.metrics.set_ready(false);
+        let mut configuration = self.configuration_mut();
+        if let Err(error) = configuration.commit() {
+            error!("Cannot commit configuration before reloading: {}", error);
+        }
+        *configuration = ConfigurationRegistry::new();
+        if let Err(error) = Self::load_configuration(&self.arguments, &mut configuration) {
+            error!("Cannot reload configuration: {}", error);
+        }
+        let mut app_manager = AppManager::new(PathBuf::from(self.arguments.data_path("bundles")));
+        app_manager.init(&mut configuration);
+        let uuids: Vec<String> = app_manager.bundles().map(|b| b.uuid.clone()).collect();
+        self.scheduler.load(&app_manager, Self::coordinates(&self.arguments, &configuration));
+        self.providers.load(&configuration);
+        *self.app_manager_mut() = app_manager;
+        self.metrics.set_ready(true);
+        self.events.publish(Event::Reloaded);
+        for uuid in uuids {
+            self.events.publish(Event::BundleLoaded { uuid });
+        }
+    }
+
+    /// Stops accepting connections, waits for in-flight requests, flushes the
+    /// configuration and closes the dashboard.
+    pub fn stop(&self) {
+        info!("Shutting down");
+        self.events.publish(Event::ShuttingDown);
+        if let Some(path) = self.control_socket.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            control::stop(&path);
+        }
+        self.scheduler.stop();
+        self.providers.stop();
+        server::stop_server(self);
+        let server_thread = self.server_thread.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(server_thread) = server_thread {
+            if server_thread.join().is_err() {
+                error!("Server thread panicked");
+            }
+        }
+        if let Err(error) = self.configuration_mut().commit() {
+            error!("Cannot commit configuration: {}", error);
+        }
+        if let Err(error) = self.dashboard.send_message(DashboardMessage::Quit) {
+            warn!("Cannot close dashboard: {}", error);
+        }
+    }
+
+    /// Shuts down gracefully on SIGTERM and SIGINT, and reloads on SIGHUP.
+    /// A second termination signal exits immediately.
+    pub fn handle_signals(&self) -> anyhow::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+        let system_state = self.clone();
+        thread::spawn(move || {
+            let mut terminating = false;
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => system_state.reload(),
+                    _ if terminating => {
+                        warn!("Received signal {} during shutdown, exiting immediately", signal);
+                        process::exit(1);
+                    }
+                    _ => {
+                        info!("Received signal {}", signal);
+                        terminating = true;
+                        system_state.shutdown.request(0);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_shutdown_request_wins() {
+        let shutdown = Shutdown::default();
+        let requester = shutdown.clone();
+        let waiter = thread::spawn(move || shutdown.wait());
+        requester.request(2);
+        requester.request(0);
+        assert_eq!(waiter.join().unwrap(), 2);
+        assert_eq!(requester.wait(), 2);
+    }
+}